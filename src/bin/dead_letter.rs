@@ -0,0 +1,28 @@
+// Lists or requeues urls that ran out of retries
+// Usage: dead_letter <config> [list|requeue]
+use anyhow::{bail, Result};
+use get_training_data::globals::{DB, FRONTIER, RETRIES};
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args: Vec<_> = std::env::args().collect();
+    match args.get(2).map(|s| s.as_str()).unwrap_or("list") {
+        "list" => {
+            let dead = RETRIES.dead()?;
+            for d in &dead {
+                println!(
+                    "{}\t{} attempt(s)\t{}",
+                    d.entry.url, d.entry.retries, d.error
+                );
+            }
+            println!("{} dead url(s)", dead.len());
+        }
+        "requeue" => {
+            let count = RETRIES.requeue_dead(&FRONTIER)?;
+            DB.flush()?;
+            println!("Requeued {} url(s)", count);
+        }
+        cmd => bail!("Unknown command: {}, expected list or requeue", cmd),
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+// Content-addressed store for raw html, so records only carry a hash & identical pages are
+// stored once
+// Blobs are gzipped & named by the sha256 of the uncompressed page:
+// {dir}/{hash[..2]}/{hash}.html.gz
+use crate::manifest::sha256;
+use crate::sink::{write_file, Compression};
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RawHtml {
+    // In the record's `raw` field
+    #[default]
+    Inline,
+    // In the blob store, the record's `raw_hash` field has its hash
+    Blob,
+    // Not saved
+    Omit,
+}
+
+pub struct Blobs {
+    dir: PathBuf,
+}
+
+impl Blobs {
+    pub fn new(dir: PathBuf) -> Blobs {
+        Blobs { dir }
+    }
+
+    // Returns the blob's hash
+    pub async fn put(&self, bytes: &[u8]) -> Result<String> {
+        let hash = sha256(bytes);
+        let dir = self.dir.join(&hash[..2]);
+        let path = dir.join(format!("{}.html.gz", hash));
+        // Another page w/ the same content was already stored
+        if path.exists() {
+            return Ok(hash);
+        }
+        tokio::fs::create_dir_all(&dir).await?;
+        let gz = Compression::Gzip.compress(bytes.to_vec()).await?;
+        // Another worker storing the same page at the same time writes the same bytes
+        write_file(&path, &gz).await?;
+        Ok(hash)
+    }
+}
@@ -4,7 +4,7 @@ use bloomfilter::Bloom;
 use log::{trace, warn};
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{BufReader, SeekFrom};
 use std::path::PathBuf;
 use std::time::Instant;
 use tokio::sync::{Mutex, RwLock};
@@ -32,7 +32,7 @@ fn checkpoint(checkpoint: &PathBuf, bloom: &Bloom<u64>) {
     let mut temp = File::create(&temp_path).unwrap();
     // Serializing directly into a file is (maybe) slow
     let bytes = bincode::serialize(&bloom).unwrap();
-    temp.write(&bytes).unwrap();
+    temp.write_all(&bytes).unwrap();
     std::fs::rename(temp_path, checkpoint).unwrap();
 }
 
@@ -64,7 +64,7 @@ impl Filter {
         if wal_path.exists() {
             let wal = File::open(&wal_path).unwrap();
             let lines: Vec<_> = BufReader::new(wal).lines().collect();
-            if lines.len() > 0 {
+            if !lines.is_empty() {
                 trace!("Saving WAL...");
                 for url in lines {
                     // https://stackoverflow.com/questions/63358858/rust-chaining-results-combinators
@@ -97,9 +97,13 @@ impl Filter {
         }
     }
 
-    pub async fn set(&self, url_hash: u64) {
+    // Returns false if the hash was already set, e.g. by another worker since it was checked
+    pub async fn set(&self, url_hash: u64) -> bool {
         let mut wal = self.wal.lock().await;
         let mut bloom = self.bloom.write().await;
+        if bloom.check(&url_hash) {
+            return false;
+        }
         writeln!(wal, "{}", url_hash).unwrap();
         bloom.set(&url_hash);
         // Drop the bloom as soon as possible so reads can continue
@@ -109,8 +113,21 @@ impl Filter {
             let bloom = self.bloom.read().await;
             checkpoint(&self.checkpoint_path, &bloom);
             wal.set_len(0).unwrap();
-            *prev_checkpoint = Box::new(Instant::now());
+            // Otherwise the next write lands at the old offset, leaving a hole of 0s
+            wal.seek(SeekFrom::Start(0)).unwrap();
+            **prev_checkpoint = Instant::now();
         }
+        true
+    }
+
+    // Checkpoints immediately (e.g. before exiting) so the WAL doesn't need replaying
+    pub async fn checkpoint(&self) {
+        let mut wal = self.wal.lock().await;
+        let bloom = self.bloom.read().await;
+        checkpoint(&self.checkpoint_path, &bloom);
+        wal.set_len(0).unwrap();
+        wal.seek(SeekFrom::Start(0)).unwrap();
+        **self.prev_checkpoint.lock().await = Instant::now();
     }
 
     pub async fn check(&self, url: u64) -> bool {
@@ -0,0 +1,155 @@
+// Url canonicalization, applied before urls are hashed for dedup & enqueued
+// `Url::parse` already lowercases hosts & drops default ports (e.g. :443 for https)
+use crate::query::edit_params;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Canonicalize {
+    pub strip_fragment: bool,
+    pub sort_query: bool,
+    // Query params to drop, a trailing "*" matches any suffix (e.g. "utm_*")
+    pub strip_params: Vec<String>,
+    // www.yelp.com -> yelp.com
+    pub fold_www: bool,
+    // /x/ -> /x (the root path is left alone), off by default since they can be different pages
+    pub strip_trailing_slash: bool,
+}
+
+impl Default for Canonicalize {
+    fn default() -> Self {
+        Canonicalize {
+            strip_fragment: true,
+            sort_query: true,
+            strip_params: vec!["utm_*".to_string()],
+            fold_www: false,
+            strip_trailing_slash: false,
+        }
+    }
+}
+
+impl Canonicalize {
+    fn is_stripped(&self, param: &str) -> bool {
+        self.strip_params.iter().any(|p| match p.strip_suffix('*') {
+            Some(prefix) => param.starts_with(prefix),
+            None => param == p,
+        })
+    }
+
+    pub fn apply(&self, url: &Url) -> Url {
+        let mut u = url.clone();
+        if self.strip_fragment {
+            u.set_fragment(None);
+        }
+        if self.fold_www {
+            let folded = u
+                .host_str()
+                .and_then(|h| h.strip_prefix("www."))
+                .map(String::from);
+            if let Some(host) = folded {
+                // Can only fail for urls w/o a host, which we just checked
+                let _ = u.set_host(Some(&host));
+            }
+        }
+        if self.strip_trailing_slash {
+            let path = u.path();
+            if path.len() > 1 && path.ends_with('/') {
+                let path = path.trim_end_matches('/').to_string();
+                u.set_path(&path);
+            }
+        }
+        edit_params(&mut u, |params| {
+            // Empty params (`a=1&&b=2`, a bare `?`) mean nothing
+            params.retain(|(k, raw)| !raw.is_empty() && !self.is_stripped(k));
+            if self.sort_query {
+                // Stable, so repeated keys keep their relative order
+                params.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+        });
+        u
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(c: &Canonicalize, url: &str) -> String {
+        c.apply(&Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn defaults() {
+        let c = Canonicalize::default();
+        assert_eq!(
+            apply(&c, "https://Example.com:443/a/?utm_source=x&b=2&a=1#top"),
+            "https://example.com/a/?a=1&b=2"
+        );
+        assert_eq!(
+            apply(&c, "https://www.example.com/x"),
+            "https://www.example.com/x"
+        );
+        assert_eq!(
+            apply(&c, "https://example.com/x?utm_source=x"),
+            "https://example.com/x"
+        );
+        assert_eq!(apply(&c, "https://example.com/x?"), "https://example.com/x");
+    }
+
+    #[test]
+    fn keeps_params_as_written() {
+        let c = Canonicalize::default();
+        for url in [
+            "https://example.com/x?a",
+            "https://example.com/x?next=/y",
+            "https://example.com/x?q=a%20b&r=a+b",
+            "https://example.com/x?tags=a,b",
+        ] {
+            assert_eq!(apply(&c, url), url);
+        }
+        // Only the order changes when sorting
+        assert_eq!(
+            apply(&c, "https://example.com/x?next=/y&a&q=a%20b"),
+            "https://example.com/x?a&next=/y&q=a%20b"
+        );
+        // Encoded keys are compared decoded
+        assert_eq!(
+            apply(&c, "https://example.com/x?b=1&utm%5Fsource=x&%61=2"),
+            "https://example.com/x?%61=2&b=1"
+        );
+    }
+
+    #[test]
+    fn repeated_keys_keep_their_order() {
+        let c = Canonicalize::default();
+        assert_eq!(
+            apply(&c, "https://example.com/x?b=2&a=3&b=1"),
+            "https://example.com/x?a=3&b=2&b=1"
+        );
+    }
+
+    #[test]
+    fn options() {
+        let c = Canonicalize {
+            strip_fragment: false,
+            sort_query: false,
+            strip_params: vec!["fbclid".to_string()],
+            fold_www: true,
+            strip_trailing_slash: true,
+        };
+        assert_eq!(
+            apply(&c, "https://www.example.com/a/b//?b=1&fbclid=x&a=2#top"),
+            "https://example.com/a/b?b=1&a=2#top"
+        );
+        assert_eq!(
+            apply(&c, "https://www.example.com/"),
+            "https://example.com/"
+        );
+        // Only the exact param is stripped w/o a trailing "*"
+        assert_eq!(
+            apply(&c, "https://example.com/x?fbclid2=x"),
+            "https://example.com/x?fbclid2=x"
+        );
+    }
+}
@@ -0,0 +1,403 @@
+// Mercator style frontier: one queue (sled tree) per host, leased from round-robin while
+// skipping hosts the limiter won't allow a request to yet.
+// Within a host entries are keyed by (priority, id) so the highest priority is leased first
+// & equal priorities are FIFO.
+// Workers lease urls instead of popping them: a leased entry is moved to the lease tree &
+// only deleted once it's acknowledged. Leases left behind by a crash are put back on startup.
+// Idle workers wait on a notification instead of polling, & the crawl is complete once
+// nothing is queued, retrying or leased (or the frontier is stopped on shutdown).
+use crate::globals::{DB, LIMITER};
+use crate::limiter::Permit;
+use anyhow::{anyhow, Result};
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use sled::{transaction::TransactionError, Transactional, Tree};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration, Instant};
+use url::Url;
+
+const SHARD_PREFIX: &str = "url_queue/";
+// Frontiers from before sharding kept every url in this tree
+const LEGACY_QUEUE: &str = "url_queue";
+
+// A leased entry remembers where it came from so it can be put back verbatim
+#[derive(Serialize, Deserialize, Debug)]
+struct Leased {
+    leased_at: u64,
+    tree: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub url: String,
+    // Hops from the seed url
+    pub depth: u32,
+    // The page the url was found on (None for seeds)
+    pub parent: Option<String>,
+    // ms since the epoch
+    pub discovered_at: u64,
+    // How many times fetching the url has failed
+    pub retries: u32,
+    // Higher is fetched sooner
+    pub priority: i64,
+}
+
+impl Entry {
+    pub fn seed(url: &Url) -> Entry {
+        Entry {
+            url: url.to_string(),
+            depth: 0,
+            parent: None,
+            discovered_at: now_ms(),
+            retries: 0,
+            priority: 0,
+        }
+    }
+
+    pub fn child(&self, url: &Url) -> Entry {
+        Entry {
+            url: url.to_string(),
+            depth: self.depth + 1,
+            parent: Some(self.url.clone()),
+            discovered_at: now_ms(),
+            retries: 0,
+            priority: 0,
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Entry> {
+        match bincode::deserialize(bytes) {
+            Ok(e) => Ok(e),
+            // Queues from before entries had metadata only stored the url
+            Err(_) => Ok(Entry::seed(&Url::parse(std::str::from_utf8(bytes)?)?)),
+        }
+    }
+}
+
+pub struct Lease {
+    id: u64,
+    pub url: Url,
+    pub entry: Entry,
+    // The host's connection & request slot, reserved when the url was leased so the request
+    // can start right away
+    pub permit: Option<Permit>,
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Sorts by priority (highest first), then by id
+fn queue_key(priority: i64, id: u64) -> Vec<u8> {
+    // Flipping the sign bit makes the i64 sort like a u64, inverting it puts
+    // the highest priority first
+    let rank = !((priority as u64) ^ (1 << 63));
+    let mut key = rank.to_be_bytes().to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+// The queue of a single host
+struct Shard {
+    host: String,
+    tree: Tree,
+}
+
+#[derive(Default)]
+struct Shards {
+    // Round-robin order
+    order: Vec<Arc<Shard>>,
+    by_host: HashMap<String, Arc<Shard>>,
+}
+
+pub enum Next {
+    Ready(Lease),
+    // Every host w/ queued urls is rate limited, try again after this long
+    Wait(Duration),
+    // Nothing can be leased until something changes (e.g. a lease is acked)
+    Empty,
+}
+
+pub struct Frontier {
+    shards: Mutex<Shards>,
+    // Index of the shard to try first next time
+    cursor: AtomicUsize,
+    leases: Tree,
+    // Urls that are queued, leased or waiting to be retried
+    // A single counter (instead of checking each tree) so completion can't be detected early
+    // while a url is moving between them
+    outstanding: AtomicUsize,
+    // Signalled whenever an idle worker might have something to do
+    notify: Notify,
+    // Set on shutdown, workers stop leasing once it is
+    stopped: AtomicBool,
+}
+
+impl Frontier {
+    pub(crate) fn new() -> Frontier {
+        let frontier = Frontier {
+            shards: Mutex::new(Shards::default()),
+            cursor: AtomicUsize::new(0),
+            leases: DB.open_tree("url_leases").unwrap(),
+            outstanding: AtomicUsize::new(0),
+            notify: Notify::new(),
+            stopped: AtomicBool::new(false),
+        };
+        for name in DB.tree_names() {
+            if let Some(host) = name.strip_prefix(SHARD_PREFIX.as_bytes()) {
+                frontier.shard(std::str::from_utf8(host).unwrap()).unwrap();
+            }
+        }
+        frontier
+    }
+
+    fn shard(&self, host: &str) -> Result<Arc<Shard>> {
+        let mut shards = self.shards.lock().unwrap();
+        if let Some(shard) = shards.by_host.get(host) {
+            return Ok(shard.clone());
+        }
+        let shard = Arc::new(Shard {
+            host: host.to_string(),
+            tree: DB.open_tree(format!("{}{}", SHARD_PREFIX, host))?,
+        });
+        shards.order.push(shard.clone());
+        shards.by_host.insert(host.to_string(), shard.clone());
+        Ok(shard)
+    }
+
+    fn all_shards(&self) -> Vec<Arc<Shard>> {
+        self.shards.lock().unwrap().order.clone()
+    }
+
+    pub fn push(&self, entry: &Entry) -> Result<()> {
+        let url = Url::parse(&entry.url)?;
+        let host = url
+            .host_str()
+            .ok_or(anyhow!("No host in url: {:?}", entry.url))?;
+        let key = queue_key(entry.priority, DB.generate_id()?);
+        self.shard(host)?
+            .tree
+            .insert(key, bincode::serialize(entry)?)?;
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    // Called once on startup (after reclaiming leases) w/ the number of urls waiting to be retried
+    pub fn count_outstanding(&self, retrying: usize) {
+        let count = self.len() + self.leases.len() + retrying;
+        self.outstanding.store(count, Ordering::SeqCst);
+    }
+
+    // A leased url was rescheduled instead of finished, so it's still outstanding after its ack
+    pub fn retrying(&self) {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    // Nothing is queued, leased or waiting to be retried
+    pub fn is_done(&self) -> bool {
+        self.outstanding.load(Ordering::SeqCst) == 0
+    }
+
+    // Waits until something changes (or `timeout` elapses)
+    pub async fn wait(&self, timeout: Option<Duration>) {
+        match timeout {
+            Some(t) => {
+                tokio::select! {
+                    _ = self.notify.notified() => {}
+                    _ = sleep(t) => {}
+                }
+            }
+            None => self.notify.notified().await,
+        }
+    }
+
+    // No new urls are handed out after this, queued ones stay queued for the next run
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+        self.notify.notify_one();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    // Wakes one idle worker, each worker that exits wakes the next so they all see completion
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    // Moves urls queued before the frontier was sharded into their host's queue
+    pub fn migrate_legacy(&self) -> Result<usize> {
+        let legacy = DB.open_tree(LEGACY_QUEUE)?;
+        let mut count = 0;
+        for kv in legacy.iter() {
+            let (k, v) = kv?;
+            self.push(&Entry::decode(&v)?)?;
+            legacy.remove(k)?;
+            count += 1;
+        }
+        if count > 0 {
+            info!("Moved {} url(s) into per-host queues", count);
+        }
+        Ok(count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.all_shards().iter().map(|s| s.tree.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all_shards().iter().all(|s| s.tree.is_empty())
+    }
+
+    pub fn hosts(&self) -> usize {
+        self.all_shards().len()
+    }
+
+    pub fn leased(&self) -> usize {
+        self.leases.len()
+    }
+    // Atomically moves `key` from `from` into the lease tree
+    // None if another worker leased it first
+    pub(crate) fn take(&self, from: &Tree, key: &[u8], entry: Entry) -> Result<Option<Lease>> {
+        let url = Url::parse(&entry.url)?;
+        let id = DB.generate_id()?;
+        let taken = (from, &self.leases)
+            .transaction(|(from_tx, leases_tx)| {
+                let value = match from_tx.remove(key)? {
+                    Some(v) => v,
+                    None => return Ok(false),
+                };
+                let leased = Leased {
+                    leased_at: now_ms(),
+                    tree: from.name().to_vec(),
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                };
+                leases_tx.insert(&id.to_be_bytes(), bincode::serialize(&leased).unwrap())?;
+                Ok(true)
+            })
+            .map_err(|e: TransactionError| anyhow!("Failed to lease: {:?}", e))?;
+        Ok(if taken {
+            Some(Lease {
+                id,
+                url,
+                entry,
+                permit: None,
+            })
+        } else {
+            None
+        })
+    }
+
+    // Round-robins over the hosts, leasing from the first one that may be fetched from now
+    // The host's next request slot is reserved w/ the lease, so other workers move on to
+    // other hosts instead of all waiting on the same one
+    pub async fn lease(&self) -> Result<Next> {
+        let shards = self.all_shards();
+        let start = self.cursor.load(Ordering::Relaxed);
+        let mut wait: Option<Instant> = None;
+        for i in 0..shards.len() {
+            let idx = (start + i) % shards.len();
+            let shard = &shards[idx];
+            if shard.tree.is_empty() {
+                continue;
+            }
+            let permit = match LIMITER.try_acquire(&shard.host).await {
+                Ok(p) => p,
+                Err(Some(at)) => {
+                    wait = Some(wait.map_or(at, |w| w.min(at)));
+                    continue;
+                }
+                // Every connection to the host is in use, acking one of them notifies us
+                Err(None) => continue,
+            };
+            // The shard can be emptied by other workers at any point (which wastes the slot)
+            while let Some((k, v)) = shard.tree.first()? {
+                let entry = Entry::decode(&v)?;
+                if let Some(mut lease) = self.take(&shard.tree, &k, entry)? {
+                    trace!("Leased from host: {}", shard.host);
+                    lease.permit = Some(permit);
+                    self.cursor.store(idx + 1, Ordering::Relaxed);
+                    return Ok(Next::Ready(lease));
+                }
+            }
+        }
+        Ok(match wait {
+            Some(at) => Next::Wait(at.saturating_duration_since(Instant::now())),
+            None => Next::Empty,
+        })
+    }
+
+    // The url is done with (processed, retried elsewhere or given up on)
+    pub fn ack(&self, lease: &Lease) -> Result<()> {
+        self.leases.remove(lease.id.to_be_bytes())?;
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+        // A connection to the host was freed up, or the crawl might be complete
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    // Puts every lease taken before `before_ms` back where it came from
+    // On startup that's every lease, since the worker holding it is gone
+    pub fn reclaim(&self, before_ms: u64) -> Result<usize> {
+        let mut count = 0;
+        for kv in self.leases.iter() {
+            let (k, v) = kv?;
+            let leased: Leased = bincode::deserialize(&v)?;
+            if leased.leased_at >= before_ms {
+                continue;
+            }
+            trace!("Reclaiming lease from: {}ms", leased.leased_at);
+            let tree = DB.open_tree(&leased.tree)?;
+            (&tree, &self.leases)
+                .transaction(|(tree_tx, leases_tx)| {
+                    tree_tx.insert(leased.key.as_slice(), leased.value.as_slice())?;
+                    leases_tx.remove(&k)?;
+                    Ok(())
+                })
+                .map_err(|e: TransactionError| anyhow!("Failed to reclaim lease: {:?}", e))?;
+            count += 1;
+        }
+        if count > 0 {
+            info!("Reclaimed {} expired lease(s)", count);
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_order() {
+        let keys = vec![
+            queue_key(i64::MAX, 5),
+            queue_key(100, 1),
+            queue_key(100, 2),
+            queue_key(1, 0),
+            queue_key(0, 3),
+            queue_key(-1, 4),
+            queue_key(-100, 0),
+            queue_key(i64::MIN, 0),
+        ];
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+    }
+}
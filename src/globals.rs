@@ -1,6 +1,16 @@
 use crate::{
+    blobs::{Blobs, RawHtml},
     bloom::{self, Filter},
+    canonical::Canonicalize,
+    frontier::Frontier,
+    limiter::{self, Limiter},
+    manifest,
+    query::QueryMatch,
+    retry::{self, Retries},
+    robots::{self, Robots},
     save::{self, Saver},
+    sink::{self, Format},
+    warc::{self, Warc},
 };
 use anyhow::{bail, Result};
 use lazy_static::lazy_static;
@@ -8,41 +18,122 @@ use log::trace;
 use regex::Regex;
 use reqwest::{
     header::{HeaderMap, HeaderValue},
-    Client, ClientBuilder,
+    redirect, Client, ClientBuilder,
 };
 use serde::{self, Deserialize, Serialize};
-use serde_json;
-use sled::{Db, Tree};
-use std::io::prelude::*;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use sled::Db;
+use std::path::PathBuf;
 use std::{
     collections::{BTreeMap, HashMap},
-    fmt::Write as fmt_write,
-    fs::{self, File},
+    fs,
+    time::Duration,
 };
 
 #[derive(Deserialize, Debug)]
 pub struct PathExcludeSettings {
     re: String,
     invert: bool,
+    // If set, a url only matches `re` if its query matches too
+    pub query: Option<QueryMatch>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LabelMaps {
-    pub domain: String,
+    // "yelp.com" only matches yelp.com, "*.yelp.com" matches any subdomain of it
+    #[serde(default)]
+    pub domains: Vec<String>,
+    // Older label maps had a single domain, it's added to `domains`
+    #[serde(default)]
+    domain: Option<String>,
     pub path_exclude: Option<PathExcludeSettings>,
+    #[serde(default)]
+    pub canonicalize: Canonicalize,
+    #[serde(default)]
+    pub priority: Priority,
     headers: Option<BTreeMap<String, String>>,
     pub maps: Vec<LabelMap>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LabelMap {
+    // Restricts the map to one of the crawled domains, same wildcard syntax as `domains`
+    pub domain: Option<String>,
     pub path_match_re: String,
+    // Further restricts which urls the map applies to by their query params
+    pub query: Option<QueryMatch>,
     pub abs_root_url: String,
     pub labels: Vec<Selector>,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Priority {
+    // Added if the url matches a label map
+    pub label_match: i64,
+    // Added if the page the url was found on matches a label map
+    pub parent_match: i64,
+    pub rules: Vec<PriorityRule>,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            label_match: 100,
+            parent_match: 50,
+            rules: vec![],
+        }
+    }
+}
+
+// Added to the priority of every url whose path matches `path_re`
+#[derive(Deserialize, Debug)]
+pub struct PriorityRule {
+    pub path_re: String,
+    pub score: i64,
+}
+
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => domain
+            .strip_suffix(parent)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => domain == pattern,
+    }
+}
+
+impl LabelMaps {
+    pub fn parse(yaml: &str) -> Result<LabelMaps> {
+        let mut maps: LabelMaps = serde_yaml::from_str(yaml)?;
+        maps.domains.extend(maps.domain.take());
+        if maps.domains.is_empty() {
+            bail!("The label map needs a list of `domains` to crawl");
+        }
+        Ok(maps)
+    }
+
+    pub fn allows_domain(&self, domain: &str) -> bool {
+        self.domains.iter().any(|d| domain_matches(d, domain))
+    }
+
+    // Every label name -> whether it's a list, a name that's a list in any map is a list
+    pub fn label_columns(&self) -> BTreeMap<String, bool> {
+        let mut columns = BTreeMap::new();
+        for label in self.maps.iter().flat_map(|m| &m.labels) {
+            *columns.entry(label.name.clone()).or_insert(false) |= label.list == Some(true);
+        }
+        columns
+    }
+}
+
+impl LabelMap {
+    pub fn allows_domain(&self, domain: &str) -> bool {
+        match &self.domain {
+            Some(d) => domain_matches(d, domain),
+            None => true,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Selector {
     pub list: Option<bool>,
@@ -54,18 +145,152 @@ pub struct Selector {
 pub struct Config {
     db_path: String,
     save_path: String,
+    // Whether a record's page is inline, in the blob store at blob_path or left out
+    #[serde(default)]
+    pub raw_html: RawHtml,
+    #[serde(default = "default_blob_path")]
+    blob_path: String,
+    // Format of the chunks in save_path, unused if `sinks` is set
+    #[serde(default)]
+    save_format: Format,
     pub filter_path: String,
     chunk_size: usize,
+    // Chunks are cut at chunk_size records, chunk_max_bytes or once the oldest record in
+    // the chunk is chunk_max_age_secs old, whichever comes first
+    chunk_max_bytes: Option<usize>,
+    #[serde(alias = "chunk_flush_secs")]
+    chunk_max_age_secs: Option<u64>,
+    // Records are assigned to a split by the hash of their url & written to a subdirectory
+    // per split, not split if empty
+    #[serde(default)]
+    splits: Vec<save::SplitConfig>,
 
     pub filter_bytes: usize,
     pub filter_expected_entries: usize,
     pub filter_checkpoint_secs: u64,
 
     pub workers: usize,
-    pub worker_check_ms: u64,
-    pub saver_check_secs: u64,
+    // Urls further than this many links from a seed aren't queued, unlimited if unset
+    pub max_depth: Option<u32>,
+
+    // Politeness limits, applied to each host separately
+    #[serde(default = "default_host_requests_per_sec")]
+    pub host_requests_per_sec: f64,
+    #[serde(default = "default_host_max_concurrent")]
+    pub host_max_concurrent: usize,
+    #[serde(default = "default_host_min_delay_ms")]
+    pub host_min_delay_ms: u64,
+    // Adaptive backoff for hosts that respond w/ 429/503
+    #[serde(default = "default_host_backoff_factor")]
+    pub host_backoff_factor: f64,
+    #[serde(default = "default_host_recovery_step")]
+    pub host_recovery_step: f64,
+    #[serde(default = "default_host_max_delay_ms")]
+    pub host_max_delay_ms: u64,
+
+    // Set to false for sites we have permission to scrape
+    #[serde(default = "default_respect_robots")]
+    pub respect_robots: bool,
+    #[serde(default = "default_robots_ttl_secs")]
+    pub robots_ttl_secs: u64,
+    // Our product token for robots.txt (e.g. "examplebot"), defaults to the first product
+    // in the user-agent header
+    pub robots_user_agent: Option<String>,
+
+    // Transient failures are retried w/ exponential backoff, then dead-lettered
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_base_ms")]
+    pub retry_base_ms: u64,
+    #[serde(default = "default_retry_max_ms")]
+    pub retry_max_ms: u64,
+    // Requests that take longer (including reading the body) fail & are retried
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+
+    // Every response is recorded to WARC files in warc_path (if set), rotated at warc_max_bytes
+    warc_path: Option<String>,
+    #[serde(default = "default_warc_max_bytes")]
+    warc_max_bytes: u64,
+
+    // How long to wait for in-flight urls on SIGINT/SIGTERM before exiting anyway
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
 
     pub label_map: String,
+
+    // Every chunk is written to each of these, a save_format sink at save_path if empty
+    #[serde(default)]
+    sinks: Vec<sink::Config>,
+}
+
+fn default_blob_path() -> String {
+    "blobs".to_string()
+}
+
+fn default_host_requests_per_sec() -> f64 {
+    2.0
+}
+
+fn default_host_max_concurrent() -> usize {
+    4
+}
+
+fn default_host_min_delay_ms() -> u64 {
+    250
+}
+
+fn default_respect_robots() -> bool {
+    true
+}
+
+// 1 day
+fn default_robots_ttl_secs() -> u64 {
+    86400
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_retry_base_ms() -> u64 {
+    1000
+}
+
+// 5 minutes
+fn default_retry_max_ms() -> u64 {
+    300_000
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_host_backoff_factor() -> f64 {
+    2.0
+}
+
+fn default_host_recovery_step() -> f64 {
+    0.05
+}
+
+fn default_host_max_delay_ms() -> u64 {
+    60_000
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+// 1GB, the usual WARC file size
+fn default_warc_max_bytes() -> u64 {
+    1_000_000_000
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -78,27 +303,45 @@ pub enum SelectorValue {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Save {
     pub url: String,
-    pub raw: String,
+    // Depending on the raw_html option the page is inline, in the blob store or not saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_hash: Option<String>,
     pub input: String,
     pub labels: BTreeMap<String, SelectorValue>,
 }
 
 lazy_static! {
-    pub static ref CONFIG: Config = {
+    static ref CONFIG_NAME: String = {
         let args: Vec<_> = std::env::args().collect();
-        let config_name = match args.get(1) {
+        match args.get(1) {
             Some(v) => v.clone(),
             None => {
                 let default_config = "config.toml";
                 trace!("Using default config: {}", default_config);
                 default_config.to_string()
             }
-        };
-        let s = fs::read_to_string(config_name).unwrap();
+        }
+    };
+    pub static ref CONFIG: Config = {
+        let s = fs::read_to_string(&*CONFIG_NAME).unwrap();
         toml::from_str(&s).unwrap()
     };
+    // Recorded w/ every shard so it's known which settings produced it
+    pub static ref CONFIG_HASH: String = {
+        let mut bytes = fs::read(&*CONFIG_NAME).unwrap();
+        bytes.extend(fs::read(&CONFIG.label_map).unwrap());
+        manifest::sha256(&bytes)
+    };
     pub static ref DB: Db = sled::open(&CONFIG.db_path).unwrap();
-    pub static ref URL_QUEUE: Tree = DB.open_tree("url_queue").unwrap();
+    pub static ref FRONTIER: Frontier = Frontier::new();
+    pub static ref RETRIES: Retries = Retries::new(retry::Config {
+        max_retries: CONFIG.max_retries,
+        base_ms: CONFIG.retry_base_ms,
+        max_ms: CONFIG.retry_max_ms,
+    });
+    pub static ref BLOBS: Blobs = Blobs::new(PathBuf::from(&CONFIG.blob_path));
     pub static ref BLOOM: Filter = {
         let filter_path = PathBuf::from(&CONFIG.filter_path);
         Filter::new(bloom::Config {
@@ -108,14 +351,41 @@ lazy_static! {
             checkpoint_secs: CONFIG.filter_checkpoint_secs,
         })
     };
+    pub static ref LIMITER: Limiter = Limiter::new(limiter::Config {
+        requests_per_sec: CONFIG.host_requests_per_sec,
+        max_concurrent: CONFIG.host_max_concurrent,
+        min_delay_ms: CONFIG.host_min_delay_ms,
+        backoff_factor: CONFIG.host_backoff_factor,
+        recovery_step: CONFIG.host_recovery_step,
+        max_delay_ms: CONFIG.host_max_delay_ms,
+    });
+    pub static ref ROBOTS: Robots = {
+        // The first product of the user-agent header if robots_user_agent isn't set
+        // e.g. "ExampleBot/1.0 (+https://example.com)" -> "ExampleBot"
+        let product_token = CONFIG.robots_user_agent.clone().unwrap_or_else(|| {
+            LABEL_MAP
+                .headers
+                .iter()
+                .flatten()
+                .find(|(k, _)| k.eq_ignore_ascii_case("user-agent"))
+                .and_then(|(_, v)| v.split(|c: char| c == '/' || c.is_whitespace()).next())
+                .filter(|t| !t.is_empty())
+                .unwrap_or("*")
+                .to_string()
+        });
+        Robots::new(robots::Config {
+            product_token,
+            ttl_secs: CONFIG.robots_ttl_secs,
+        })
+    };
     pub static ref LABEL_MAP: LabelMaps = {
-        serde_yaml::from_str::<LabelMaps>(&fs::read_to_string(&CONFIG.label_map).unwrap()).unwrap()
+        LabelMaps::parse(&fs::read_to_string(&CONFIG.label_map).unwrap()).unwrap()
     };
     pub static ref EXCLUDE_RE: Option<Regex> = {
         LABEL_MAP
             .path_exclude
             .as_ref()
-            .and_then(|s| Some(Regex::new(&s.re).unwrap()))
+            .map(|s| Regex::new(&s.re).unwrap())
     };
     pub static ref INVERT_EXCLUDE: bool = {
         if LABEL_MAP.path_exclude.is_none() {
@@ -127,55 +397,130 @@ lazy_static! {
         let mut re_map = HashMap::new();
         for map in LABEL_MAP.maps.iter() {
             let re = &map.path_match_re;
-            re_map.insert(re.clone(), Regex::new(&re).unwrap());
+            re_map.insert(re.clone(), Regex::new(re).unwrap());
         }
         re_map
     };
-    pub static ref CLIENT: Client = {
-        let client = match &LABEL_MAP.headers {
-            Some(h) => {
-                let mut headers = HeaderMap::new();
-                for (k, v) in h {
-                    headers.insert(k.as_str(), HeaderValue::from_str(&v).unwrap());
-                }
-                ClientBuilder::new()
-                    .default_headers(headers)
-                    .gzip(true)
-                    .brotli(true)
-                    .build()
-                    .unwrap()
+    pub static ref PRIORITY_RE: HashMap<String, Regex> = {
+        let mut re_map = HashMap::new();
+        for rule in LABEL_MAP.priority.rules.iter() {
+            let re = &rule.path_re;
+            re_map.insert(re.clone(), Regex::new(re).unwrap());
+        }
+        re_map
+    };
+    pub static ref QUERY_VALUE_RE: HashMap<String, Regex> = {
+        let mut re_map = HashMap::new();
+        let exclude = LABEL_MAP
+            .path_exclude
+            .as_ref()
+            .and_then(|e| e.query.as_ref());
+        let maps = LABEL_MAP.maps.iter().filter_map(|m| m.query.as_ref());
+        for query in exclude.into_iter().chain(maps) {
+            for re in query.values.values() {
+                re_map.insert(re.clone(), Regex::new(re).unwrap());
             }
-            None => Client::new(),
-        };
-        client
+        }
+        re_map
+    };
+    // Sent w/ every request
+    pub static ref HEADERS: HeaderMap = {
+        let mut headers = HeaderMap::new();
+        for (k, v) in LABEL_MAP.headers.iter().flatten() {
+            headers.insert(k.as_str(), HeaderValue::from_str(v).unwrap());
+        }
+        headers
+    };
+    pub static ref CLIENT: Client = {
+        // W/o a timeout a stalled server would hold a worker (& a connection to the host) forever
+        let builder = ClientBuilder::new()
+            .timeout(Duration::from_secs(CONFIG.request_timeout_secs))
+            .connect_timeout(Duration::from_secs(CONFIG.connect_timeout_secs))
+            // Followed in `warc::get`, so each hop is recorded
+            .redirect(redirect::Policy::none());
+        match &LABEL_MAP.headers {
+            Some(_) => builder
+                .default_headers(HEADERS.clone())
+                .gzip(true)
+                .brotli(true),
+            None => builder,
+        }
+        .build()
+        .unwrap()
     };
+    pub static ref WARC: Option<Warc> = CONFIG.warc_path.as_ref().map(|dir| {
+        Warc::new(warc::Config {
+            dir: PathBuf::from(dir),
+            max_bytes: CONFIG.warc_max_bytes,
+        })
+    });
     pub static ref SAVER: Saver<String> = {
-        fn save(idx: usize, v: &Vec<String>) -> Result<()> {
-            trace!("Saving chunk: {}...", idx);
-            let path = format!("{}/{}.json", CONFIG.save_path, idx);
-            if Path::new(&path).exists() {
-                bail!("Path: {} already exists", path);
+        let default = [sink::Config {
+            format: CONFIG.save_format,
+            path: CONFIG.save_path.clone(),
+        }];
+        let configs = if CONFIG.sinks.is_empty() {
+            &default[..]
+        } else {
+            &CONFIG.sinks[..]
+        };
+        // Each dir has its own manifest
+        for (i, c) in configs.iter().enumerate() {
+            if configs[..i].iter().any(|o| o.path == c.path) {
+                panic!("More than one sink writes to: {}", c.path);
             }
-            let mut f = File::create(path)?;
-            let mut json_arr: String = format!("[{}", v[0]);
-            for val in v[1..].iter() {
-                write!(json_arr, ", {}", val)?;
+        }
+        for (i, s) in CONFIG.splits.iter().enumerate() {
+            if CONFIG.splits[..i].iter().any(|o| o.name == s.name) {
+                panic!("Split: {} is configured more than once", s.name);
             }
-            write!(json_arr, "]")?;
-            f.write_all(json_arr.as_bytes())?;
-            Ok(())
         }
-
-        fs::create_dir_all(&CONFIG.save_path).unwrap();
-        let paths = fs::read_dir(&CONFIG.save_path).unwrap();
+        let labels = LABEL_MAP.label_columns();
+        let open = |split: Option<&str>| {
+            let configs: Vec<_> = configs.iter().map(|c| c.split(split)).collect();
+            // Only used if the chunk sequence isn't in the db yet
+            let last_chunk = configs.iter().map(|c| c.last_chunk()).max().unwrap();
+            let sinks = configs
+                .iter()
+                .map(|c| c.open(&labels, CONFIG.chunk_size, &CONFIG_HASH))
+                .collect();
+            (sinks, last_chunk + 1)
+        };
 
         Saver::new(
-            save,
+            &open,
             save::Config {
-                check_interval_secs: CONFIG.saver_check_secs,
                 chunk_size: CONFIG.chunk_size,
-                start_chunk: paths.count() + 1,
+                max_bytes: CONFIG.chunk_max_bytes,
+                max_age: CONFIG.chunk_max_age_secs.map(Duration::from_secs),
+                splits: CONFIG.splits.clone(),
             },
         )
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domains() {
+        assert!(domain_matches("yelp.com", "yelp.com"));
+        assert!(!domain_matches("yelp.com", "www.yelp.com"));
+        assert!(domain_matches("*.yelp.com", "www.yelp.com"));
+        assert!(domain_matches("*.yelp.com", "a.b.yelp.com"));
+        assert!(!domain_matches("*.yelp.com", "yelp.com"));
+        assert!(!domain_matches("*.yelp.com", "notyelp.com"));
+        assert!(!domain_matches("*.yelp.com", ".yelp.com"));
+    }
+
+    #[test]
+    fn legacy_domain() {
+        let maps = LabelMaps::parse("domain: yelp.com\nmaps: []").unwrap();
+        assert_eq!(maps.domains, vec!["yelp.com"]);
+        assert!(maps.allows_domain("yelp.com"));
+        let maps = LabelMaps::parse("domains: ['*.yelp.com']\nmaps: []").unwrap();
+        assert!(maps.allows_domain("www.yelp.com"));
+        assert!(LabelMaps::parse("maps: []").is_err());
+    }
+}
@@ -1,3 +1,13 @@
+pub mod blobs;
 pub mod bloom;
+mod canonical;
+pub mod frontier;
 pub mod globals;
+pub mod limiter;
+mod manifest;
+pub mod query;
+pub mod retry;
+mod robots;
 mod save;
+pub mod sink;
+pub mod warc;
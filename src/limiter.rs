@@ -0,0 +1,218 @@
+// Per-host politeness: caps concurrent connections & spaces out requests to each host
+// The gap between requests adapts (AIMD): it grows multiplicatively when a host
+// pushes back (429/503) & shrinks additively back to the configured rate on success
+use log::{trace, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep_until, Duration, Instant};
+
+pub struct Config {
+    // <= 0 disables the rate limit (the min delay still applies)
+    pub requests_per_sec: f64,
+    pub max_concurrent: usize,
+    pub min_delay_ms: u64,
+    // The gap is multiplied by this when a host pushes back
+    pub backoff_factor: f64,
+    // Requests/sec regained after every successful request
+    pub recovery_step: f64,
+    // The gap never grows past this (Retry-After can still pause longer)
+    pub max_delay_ms: u64,
+}
+
+struct Schedule {
+    // Earliest time the next request to this host may start
+    next_slot: Instant,
+    // Current gap between requests
+    interval: Duration,
+    // The gap `interval` recovers to, includes the robots.txt Crawl-delay
+    floor: Duration,
+}
+
+struct Host {
+    connections: Arc<Semaphore>,
+    schedule: Mutex<Schedule>,
+}
+
+pub struct Limiter {
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+    // Minimum time between the start of 2 requests to the same host
+    interval: Duration,
+    max_concurrent: usize,
+    backoff_factor: f64,
+    recovery_step: f64,
+    max_interval: Duration,
+}
+
+// Held for the duration of a request, dropping it frees up a connection slot for the host
+pub struct Permit {
+    _connection: OwnedSemaphorePermit,
+}
+
+impl Limiter {
+    pub fn new(c: Config) -> Limiter {
+        let Config {
+            requests_per_sec,
+            max_concurrent,
+            min_delay_ms,
+            backoff_factor,
+            recovery_step,
+            max_delay_ms,
+        } = c;
+        let rate_interval = if requests_per_sec > 0.0 {
+            Duration::from_secs_f64(1.0 / requests_per_sec)
+        } else {
+            Duration::from_secs(0)
+        };
+        Limiter {
+            hosts: Mutex::new(HashMap::new()),
+            interval: rate_interval.max(Duration::from_millis(min_delay_ms)),
+            // A limit of 0 would deadlock every worker
+            max_concurrent: max_concurrent.max(1),
+            backoff_factor: backoff_factor.max(1.0),
+            recovery_step,
+            max_interval: Duration::from_millis(max_delay_ms),
+        }
+    }
+
+    async fn host(&self, host: &str) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().await;
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                Arc::new(Host {
+                    connections: Arc::new(Semaphore::new(self.max_concurrent)),
+                    schedule: Mutex::new(Schedule {
+                        next_slot: Instant::now(),
+                        interval: self.interval,
+                        floor: self.interval,
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    // Waits until a request to `host` is allowed
+    pub async fn acquire(&self, host: &str) -> Permit {
+        let h = self.host(host).await;
+        // The semaphore is never closed
+        let connection = h.connections.clone().acquire_owned().await.unwrap();
+        let slot = {
+            let mut schedule = h.schedule.lock().await;
+            let slot = schedule.next_slot.max(Instant::now());
+            schedule.next_slot = slot + schedule.interval;
+            slot
+        };
+        if slot > Instant::now() {
+            trace!("Waiting {:?} for host: {}", slot - Instant::now(), host);
+            sleep_until(slot).await;
+        }
+        Permit {
+            _connection: connection,
+        }
+    }
+
+    // Takes a connection & the next request slot of `host` if a request could start now
+    // Otherwise Err w/ when the next slot is, or None if all its connections are in use
+    pub async fn try_acquire(&self, host: &str) -> Result<Permit, Option<Instant>> {
+        let h = self.host(host).await;
+        let connection = match h.connections.clone().try_acquire_owned() {
+            Ok(c) => c,
+            Err(_) => return Err(None),
+        };
+        let mut schedule = h.schedule.lock().await;
+        let now = Instant::now();
+        if schedule.next_slot > now {
+            return Err(Some(schedule.next_slot));
+        }
+        schedule.next_slot = now + schedule.interval;
+        Ok(Permit {
+            _connection: connection,
+        })
+    }
+
+    // Raises the gap between requests to `host` (e.g. robots.txt Crawl-delay)
+    // Never lowers it below the configured interval
+    pub async fn set_min_delay(&self, host: &str, delay: Duration) {
+        let h = self.host(host).await;
+        let mut schedule = h.schedule.lock().await;
+        schedule.floor = self.interval.max(delay);
+        schedule.interval = schedule.interval.max(schedule.floor);
+    }
+
+    // The host is throttling us (429/503): slow down & honour Retry-After if it was sent
+    pub async fn backoff(&self, host: &str, retry_after: Option<Duration>) {
+        let h = self.host(host).await;
+        let mut schedule = h.schedule.lock().await;
+        // An interval of 0 can't be multiplied up, so start from 1 request/sec
+        let interval = if schedule.interval == Duration::from_secs(0) {
+            Duration::from_secs(1)
+        } else {
+            schedule.interval
+        };
+        schedule.interval = interval
+            .mul_f64(self.backoff_factor)
+            .min(self.max_interval)
+            .max(schedule.floor);
+        let mut resume = Instant::now() + schedule.interval;
+        if let Some(retry_after) = retry_after {
+            resume = resume.max(Instant::now() + retry_after);
+        }
+        schedule.next_slot = schedule.next_slot.max(resume);
+        warn!(
+            "Backing off host: {} for {:?}, gap between requests is now {:?}",
+            host,
+            resume - Instant::now(),
+            schedule.interval
+        );
+    }
+
+    // Additive increase: every success lets the host go `recovery_step` requests/sec faster
+    pub async fn success(&self, host: &str) {
+        let h = self.host(host).await;
+        let mut schedule = h.schedule.lock().await;
+        if schedule.interval <= schedule.floor {
+            return;
+        }
+        let rate = 1.0 / schedule.interval.as_secs_f64() + self.recovery_step;
+        schedule.interval = Duration::from_secs_f64(1.0 / rate).max(schedule.floor);
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(v: &str) -> Option<Duration> {
+    let v = v.trim();
+    if let Ok(secs) = v.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(v).ok()?;
+    // A date in the past means "retry now"
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or_else(|_| Duration::from_secs(0)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_secs() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::from_secs(0)));
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let at = SystemTime::now() + Duration::from_secs(60);
+        let wait = parse_retry_after(&httpdate::fmt_http_date(at)).unwrap();
+        // HTTP dates only have second precision
+        assert!(wait > Duration::from_secs(58) && wait <= Duration::from_secs(60));
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(parse_retry_after(&past), Some(Duration::from_secs(0)));
+    }
+}
@@ -1,28 +1,57 @@
-use anyhow::{anyhow, bail, Result};
-use env_logger;
+use anyhow::{anyhow, bail, Context, Result};
 use fasthash::metro::hash64;
-use futures;
-use get_training_data::globals::{
-    LabelMap, Save, SelectorValue, BLOOM, CLIENT, CONFIG, DB, EXCLUDE_RE, INVERT_EXCLUDE,
-    LABEL_MAP, MATCH_RE, SAVER, URL_QUEUE,
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use get_training_data::{
+    blobs::RawHtml,
+    frontier::{self, Entry, Lease, Next},
+    globals::{
+        LabelMap, Save, SelectorValue, BLOBS, BLOOM, CONFIG, DB, EXCLUDE_RE, FRONTIER,
+        INVERT_EXCLUDE, LABEL_MAP, LIMITER, MATCH_RE, PRIORITY_RE, RETRIES, ROBOTS, SAVER, WARC,
+    },
+    limiter, query,
+    retry::{self, Fatal, StatusError},
+    warc,
 };
 use kuchiki::{self, traits::*, NodeRef};
-use log::{debug, error, info, trace, warn};
-use reqwest::StatusCode;
-use std::collections::BTreeMap;
-use tokio::{
-    self,
-    time::{sleep, Duration},
-};
+use log::{error, info, trace, warn};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use std::collections::{BTreeMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
 use url::Url;
 
-async fn fetch(url: Url) -> Result<Vec<u8>> {
-    let resp = CLIENT.get(url).send().await?;
-    if resp.status() != StatusCode::OK {
-        bail!("Received status code: {}", resp.status());
+// For the summary printed on exit
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+// `permit` is the host's connection & request slot if it was reserved already
+async fn fetch(url: Url, permit: Option<limiter::Permit>) -> Result<warc::Fetched> {
+    let host = url
+        .host_str()
+        .ok_or(anyhow!("No host in url: {:?}", url))?
+        .to_string();
+    // Hold the permit until the body is read so it counts as an open connection
+    let _permit = match permit {
+        Some(p) => p,
+        None => LIMITER.acquire(&host).await,
+    };
+    let resp = warc::get(&url).await?;
+    let status = resp.status;
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = resp
+            .headers
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(limiter::parse_retry_after);
+        LIMITER.backoff(&host, retry_after).await;
     }
-    let bytes = resp.bytes().await?;
-    Ok(bytes.to_vec())
+    if status != StatusCode::OK {
+        return Err(StatusError(status).into());
+    }
+    LIMITER.success(&host).await;
+    Ok(resp)
 }
 
 fn get_training_input(root: &NodeRef) -> Option<String> {
@@ -40,7 +69,7 @@ fn get_training_input(root: &NodeRef) -> Option<String> {
         } else if let Some(text_node) = child.as_text() {
             let text = text_node.borrow();
             let trimmed = text.trim();
-            if trimmed.len() > 0 {
+            if !trimmed.is_empty() {
                 // TODO: The tokenizer might already handle this
                 let trimmed = trimmed.replace("\u{a0}", " ");
                 out.push_str(&trimmed);
@@ -69,20 +98,52 @@ fn get_training_input(root: &NodeRef) -> Option<String> {
     }
 }
 
-fn get_training_output(page: &NodeRef, url: &Url) -> BTreeMap<String, SelectorValue> {
-    let mut path = url.path();
-    if path.starts_with("/") {
-        path = &path[1..];
+// The label map regexes match against the path w/o the leading "/"
+fn match_path(url: &Url) -> &str {
+    let path = url.path();
+    path.strip_prefix('/').unwrap_or(path)
+}
+
+// Label maps whose domain & path regex match, ignoring their query rules
+fn path_matching_maps(url: &Url) -> impl Iterator<Item = &'static LabelMap> + '_ {
+    let domain = url.domain().unwrap_or_default();
+    LABEL_MAP.maps.iter().filter(move |m| {
+        let re = MATCH_RE.get(&m.path_match_re).unwrap();
+        m.allows_domain(domain) && re.is_match(match_path(url))
+    })
+}
+
+fn matching_maps(url: &Url) -> impl Iterator<Item = &'static LabelMap> + '_ {
+    path_matching_maps(url).filter(move |m| match &m.query {
+        Some(q) => q.matches(url),
+        None => true,
+    })
+}
+
+// Urls likely to produce training data are fetched first
+fn priority(url: &Url, parent: Option<&Url>) -> i64 {
+    let settings = &LABEL_MAP.priority;
+    let mut score = 0;
+    if matching_maps(url).next().is_some() {
+        score += settings.label_match;
+    }
+    if parent.is_some_and(|p| matching_maps(p).next().is_some()) {
+        score += settings.parent_match;
+    }
+    for rule in &settings.rules {
+        if PRIORITY_RE
+            .get(&rule.path_re)
+            .unwrap()
+            .is_match(match_path(url))
+        {
+            score += rule.score;
+        }
     }
+    score
+}
 
-    let matches: Vec<_> = LABEL_MAP
-        .maps
-        .iter()
-        .filter(|m| {
-            let re = MATCH_RE.get(&m.path_match_re).unwrap();
-            re.is_match(path)
-        })
-        .collect();
+fn get_training_output(page: &NodeRef, url: &Url) -> BTreeMap<String, SelectorValue> {
+    let matches: Vec<_> = matching_maps(url).collect();
 
     if matches.len() > 1 {
         warn!("Multiple ({}) label maps for url: {:?}", matches.len(), url);
@@ -143,54 +204,65 @@ fn get_links(page: &NodeRef, cur: &Url) -> Vec<Url> {
         .filter_map(|l| {
             let attrs = l.attributes.borrow();
             // Theoretically this should never return None since we already filter by a[href]
-            attrs.get("href").and_then(|l| Some(String::from(l)))
+            attrs.get("href").map(String::from)
         })
         .collect();
     if links.len() != old_len {
         warn!("Links dropped: {} -> {}", old_len, links.len());
     }
 
-    let target_domain = &LABEL_MAP.domain;
-    let scheme = cur.scheme();
+    // Relative links are resolved against <base href> if the page has one
+    let base = page
+        .select_first("base[href]")
+        .ok()
+        .and_then(|el| {
+            let attrs = el.attributes.borrow();
+            attrs.get("href").and_then(|href| cur.join(href).ok())
+        })
+        .unwrap_or_else(|| cur.clone());
+
     // Slower than using a single `filter_map` above but the "Links dropped" check is probably good
     let links: Vec<_> = links
         .iter()
         .filter_map(|l| {
             // remove links to the same page
-            if l.starts_with("#") {
+            if l.starts_with('#') {
                 return None;
             };
 
-            let u = if l.starts_with("/") {
-                Url::parse(&format!("{}://{}{}", scheme, target_domain, l)).ok()
-            } else {
-                Url::parse(&l).ok().and_then(|u| {
-                    let domain = match u.domain() {
-                        Some(d) => d,
-                        None => return None,
-                    };
-                    if domain == target_domain {
-                        Some(u)
-                    } else {
-                        None
-                    }
-                })
-            };
+            // Handles absolute, protocol-relative (//host/x), root-relative (/x)
+            // & relative (x.html, ../x, ?page=2) links
+            let u = base.join(l.trim()).ok().and_then(|u| {
+                // mailto:, javascript:, etc.
+                if u.scheme() != "http" && u.scheme() != "https" {
+                    return None;
+                }
+                // Canonicalize first so e.g. folded www. links pass the domain check
+                let u = normalize(&u);
+                if LABEL_MAP.allows_domain(u.domain()?) {
+                    Some(u)
+                } else {
+                    None
+                }
+            });
             // EXCLUDE_RE is not a real option (even though we can call option methods on it)
             // so we can't do `u.and(EXCLUDE_RE)`
             // TODO: We can probably dereference it (*EXCLUDE_RE)
             let u = match EXCLUDE_RE.as_ref().and(u) {
                 Some(u) => {
-                    let mut path = u.path();
-                    if !path.starts_with("/") {
-                        warn!("Path: {} did not start with \"/\"", path);
-                    }
-                    // chop off the "/"
-                    path = &path[1..];
+                    let path = match_path(&u);
                     let exclude = EXCLUDE_RE.as_ref().unwrap();
                     // INVERT_EXCLUDE == true: "exclude every that does not match this regex"
                     // INVERT_EXCLUDE == false: "exclude every that does match this regex"
-                    let is_match = exclude.is_match(path);
+                    let query_match = LABEL_MAP
+                        .path_exclude
+                        .as_ref()
+                        .and_then(|e| e.query.as_ref());
+                    let is_match = exclude.is_match(path)
+                        && match query_match {
+                            Some(q) => q.matches(&u),
+                            None => true,
+                        };
                     if *INVERT_EXCLUDE {
                         if is_match {
                             Some(u)
@@ -214,32 +286,48 @@ fn get_links(page: &NodeRef, cur: &Url) -> Vec<Url> {
 }
 
 // all non-fatal errors bubble up to this function
-async fn process(url: &Url) -> Result<()> {
-    let links = {
-        let bytes = fetch(url.clone()).await?;
+async fn process(lease: &Lease, permit: Option<limiter::Permit>) -> Result<()> {
+    // Links & labels are relative to where the page ended up, after any redirects
+    let fetched = fetch(lease.url.clone(), permit).await?;
+    let url = &fetched.url;
+    let bytes = fetched.body;
+    // The page can't be held across an await, since it isn't Send
+    let (links, training) = {
         // TODO: Is there a way to do this w/o clone?
         let page_str = String::from_utf8(bytes.clone())?;
         let page = kuchiki::parse_html().one(page_str.as_str());
         let input = get_training_input(&page).ok_or(anyhow!("No training input for: {:?}", url))?;
-        let output = get_training_output(&page, &url);
-
-        if output.len() > 0 {
-            let save = Save {
-                url: url.to_string(),
-                raw: page_str,
-                input,
-                labels: output,
-            };
-            let json_str = serde_json::to_string(&save).unwrap();
-            SAVER.add(json_str);
-        }
-
-        get_links(&page, &url)
+        let output = get_training_output(&page, url);
+        let training = if output.is_empty() {
+            None
+        } else {
+            Some((page_str, input, output))
+        };
+        (get_links(&page, url), training)
     };
+
+    if let Some((page_str, input, output)) = training {
+        let (raw, raw_hash) = match CONFIG.raw_html {
+            RawHtml::Inline => (Some(page_str), None),
+            // Dropping the page would leave a hole in the dataset, so this stops the crawl
+            RawHtml::Blob => (None, Some(BLOBS.put(&bytes).await.context(Fatal)?)),
+            RawHtml::Omit => (None, None),
+        };
+        let save = Save {
+            url: url.to_string(),
+            raw,
+            raw_hash,
+            input,
+            labels: output,
+        };
+        let json_str = serde_json::to_string(&save).unwrap();
+        SAVER.add(url.as_str(), json_str);
+    }
+
     let mut urls_added: usize = 0;
-    for url in &links {
-        if add_url(url).await.unwrap() {
-            trace!("Added url: {}", url.to_string());
+    for link in &links {
+        if add_url(link, Some(&lease.entry)).await? {
+            trace!("Added url: {}", link.to_string());
             urls_added += 1;
         }
     }
@@ -251,83 +339,260 @@ async fn process(url: &Url) -> Result<()> {
     Ok(())
 }
 
+// Urls due for a retry go first
+async fn next_lease() -> Result<Next> {
+    let retry = match RETRIES.lease_due(&FRONTIER).await? {
+        Next::Ready(lease) => return Ok(Next::Ready(lease)),
+        next => next,
+    };
+    Ok(match (FRONTIER.lease().await?, retry) {
+        (Next::Ready(lease), _) => Next::Ready(lease),
+        (Next::Wait(a), Next::Wait(b)) => Next::Wait(a.min(b)),
+        (Next::Wait(d), _) | (_, Next::Wait(d)) => Next::Wait(d),
+        _ => Next::Empty,
+    })
+}
+
 async fn worker() -> Result<()> {
     trace!("Running worker...");
     loop {
+        if FRONTIER.is_stopped() {
+            trace!("Shutting down, stopping worker...");
+            FRONTIER.wake();
+            return Ok(());
+        }
         // ? operator for fatal errors
-        let url = match URL_QUEUE.pop_min()? {
-            Some((_, v)) => {
-                let bytes = v.to_vec();
-                let s = String::from_utf8(bytes)?;
-                Url::parse(&s)?
+        let mut lease = match next_lease().await? {
+            Next::Ready(l) => l,
+            Next::Wait(d) => {
+                trace!("Waiting for: {:?} (rate limits or retry backoff)", d);
+                FRONTIER.wait(Some(d)).await;
+                continue;
             }
-            None => {
-                trace!("No work, sleeping...");
-                sleep(Duration::from_millis(CONFIG.worker_check_ms)).await;
+            Next::Empty => {
+                if FRONTIER.is_done() {
+                    trace!("Crawl complete, stopping worker...");
+                    FRONTIER.wake();
+                    return Ok(());
+                }
+                // Other workers are still busy, pushing or acking a url notifies us
+                trace!("No work, waiting...");
+                FRONTIER.wait(None).await;
                 continue;
             }
         };
 
-        if let Err(e) = process(&url).await {
+        // Moved out so the host's connection is freed as soon as the page is fetched
+        let permit = lease.permit.take();
+        let result = handle(&lease, permit).await;
+        // Acked even if handling failed (the error is fatal anyway), otherwise the crawl
+        // could never complete
+        FRONTIER.ack(&lease)?;
+        result?;
+    }
+}
+
+// Processes the leased url & reschedules it if that failed transiently
+// Only returns fatal errors, e.g. the url couldn't be rescheduled or stored
+async fn handle(lease: &Lease, permit: Option<limiter::Permit>) -> Result<()> {
+    let url = &lease.url;
+    // A page that panics the parser is treated like any other failure
+    let result = AssertUnwindSafe(process(lease, permit))
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Panicked while processing url")));
+    if let Err(e) = result {
+        if e.is::<Fatal>() {
+            // Requeued so the url is processed again on the next run
+            FRONTIER.push(&lease.entry)?;
+            return Err(e);
+        }
+        FAILED.fetch_add(1, Ordering::Relaxed);
+        if !retry::is_transient(&e) {
             warn!("error processing url: {:?}. {:?}", url.to_string(), e);
+        } else if RETRIES.schedule(&FRONTIER, &lease.entry, &e)? {
+            warn!("retrying url: {:?} after error: {:?}", url.to_string(), e);
+        } else {
+            error!("giving up on url: {:?}. {:?}", url.to_string(), e);
         }
+    } else {
+        PROCESSED.fetch_add(1, Ordering::Relaxed);
     }
+    Ok(())
 }
 
-async fn add_url(s: &Url) -> Result<bool> {
-    let bytes = s.as_str().as_bytes();
-    let hash = hash64(bytes);
-    Ok(if !BLOOM.check(hash).await {
-        let id = DB.generate_id()?;
-        BLOOM.set(hash).await;
-        URL_QUEUE.insert(id.to_be_bytes(), bytes)?;
-        true
-    } else {
-        false
-    })
+// Waits for every worker, the first one to fail stops the rest & its error is returned
+async fn join_workers(handles: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    let mut workers: FuturesUnordered<_> = handles.into_iter().collect();
+    let mut result = Ok(());
+    while let Some(r) = workers.next().await {
+        // Panics outside of `process` end up here too
+        if let Err(e) = r.map_err(anyhow::Error::from).and_then(|r| r) {
+            if result.is_ok() {
+                error!("Worker failed, stopping: {:?}", e);
+                FRONTIER.stop();
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+// Canonicalizes the url & drops the query params its label maps don't care about
+fn normalize(url: &Url) -> Url {
+    let url = LABEL_MAP.canonicalize.apply(url);
+    let maps: Vec<_> = path_matching_maps(&url).collect();
+    if maps.is_empty() {
+        return url;
+    }
+    let mut keep = HashSet::new();
+    for map in maps {
+        match map.query.as_ref().and_then(|q| q.allowed.as_ref()) {
+            Some(allowed) => keep.extend(allowed.iter().map(|k| k.as_str())),
+            // A map w/o an allow list needs every param
+            None => return url,
+        }
+    }
+    query::retain_params(&url, &keep)
+}
+
+// `parent` is the entry of the page the url was found on, None for seeds
+async fn add_url(s: &Url, parent: Option<&Entry>) -> Result<bool> {
+    // Variants of the same page (fragments, param order, etc.) must hash the same
+    let s = &normalize(s);
+    let mut entry = match parent {
+        Some(p) => p.child(s),
+        None => Entry::seed(s),
+    };
+    if let Some(max_depth) = CONFIG.max_depth {
+        // Checked before the bloom filter so the url can still be added if it's found closer to a seed
+        if entry.depth > max_depth {
+            return Ok(false);
+        }
+    }
+    let hash = hash64(s.as_str().as_bytes());
+    if BLOOM.check(hash).await {
+        return Ok(false);
+    }
+    // Not set in the bloom filter, so the url is checked again if the rules change
+    if CONFIG.respect_robots && !ROBOTS.allowed(s).await? {
+        trace!("Disallowed by robots.txt: {}", s);
+        return Ok(false);
+    }
+    let parent_url = match parent {
+        Some(p) => Some(Url::parse(&p.url)?),
+        None => None,
+    };
+    entry.priority = priority(s, parent_url.as_ref());
+    if !BLOOM.set(hash).await {
+        return Ok(false);
+    }
+    FRONTIER.push(&entry)?;
+    Ok(true)
+}
+
+// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+    let started = Instant::now();
 
-    // Use a blocking thread for the saver b/c I'm too lazy to figure
-    // out how to store async function pointers in the save method
-    // w/o async function pointers we have to use blocking IO
-    // which blocks the underlying tokio thread
-    // giving the saver its own dedicated thread should prevent issues
-    tokio::task::spawn_blocking(move || {
-        futures::executor::block_on(async move {
-            if let Err(e) = SAVER.run().await {
-                error!("Error saving: {:?}", e);
-                std::process::exit(1);
-            }
-        });
+    // Sinks do async IO, so the saver is just another task
+    let saver = tokio::spawn(async move {
+        if let Err(e) = SAVER.run().await {
+            error!("Error saving: {:?}", e);
+            std::process::exit(1);
+        }
     });
 
-    if URL_QUEUE.is_empty() {
+    // Any lease from before now belongs to a worker of a previous (crashed) run
+    FRONTIER.reclaim(frontier::now_ms())?;
+    FRONTIER.migrate_legacy()?;
+    FRONTIER.count_outstanding(RETRIES.len());
+
+    if FRONTIER.is_empty() {
         let root_urls = &LABEL_MAP.maps.len();
         for map in &LABEL_MAP.maps {
             let url = Url::parse(&map.abs_root_url)?;
-            add_url(&url).await?;
+            add_url(&url, None).await?;
         }
-        if URL_QUEUE.is_empty() {
+        // Urls waiting to be retried are still work, even though their seeds were seen already
+        if FRONTIER.is_empty() && RETRIES.is_empty() {
             bail!("URL queue is empty after adding {} root url(s) from label maps. We are either completely out of URLs or there's a bug.", root_urls);
         }
     }
-    info!("Starting with: {} urls", URL_QUEUE.len());
+    info!(
+        "Starting with: {} urls across {} host(s), {} waiting to be retried",
+        FRONTIER.len(),
+        FRONTIER.hosts(),
+        RETRIES.len()
+    );
     // If we don't wait on  the join handles then
     // we can't use async inside the workers b/c the
     // runtime terminates?!
     let mut handles = vec![];
     for _ in 0..CONFIG.workers {
-        let handle = tokio::spawn(async move {
-            worker().await.unwrap();
-        });
-        handles.push(handle);
+        handles.push(tokio::spawn(worker()));
     }
 
-    futures::future::join_all(handles).await;
+    let workers = join_workers(handles);
+    tokio::pin!(workers);
+    // A failed worker still lets the others finish their urls & everything be persisted,
+    // its error is returned after that
+    let result = tokio::select! {
+        r = &mut workers => {
+            if r.is_ok() {
+                info!("Crawl complete, all workers stopped");
+            }
+            r
+        }
+        _ = shutdown_signal() => {
+            info!("Shutting down, waiting for in-flight urls...");
+            FRONTIER.stop();
+            let timeout = Duration::from_secs(CONFIG.shutdown_timeout_secs);
+            tokio::select! {
+                r = &mut workers => {
+                    info!("All workers stopped");
+                    r
+                }
+                _ = sleep(timeout) => {
+                    // Their leases are put back in the queue on the next startup
+                    warn!("Workers didn't stop within {:?}, {} url(s) still leased", timeout, FRONTIER.leased());
+                    Ok(())
+                }
+                _ = shutdown_signal() => {
+                    warn!("Received a second signal, not waiting for in-flight urls");
+                    Ok(())
+                }
+            }
+        }
+    };
 
-    Ok(())
+    // Write the last (partial) chunk & persist everything so a restart doesn't redo work
+    SAVER.close().await?;
+    saver.await?;
+    BLOOM.checkpoint().await;
+    if let Some(warc) = &*WARC {
+        warc.close().await?;
+    }
+    DB.flush_async().await?;
+
+    info!(
+        "Finished in {:?}: {} page(s) processed, {} failed, {} record(s) saved, {} dead url(s)",
+        started.elapsed(),
+        PROCESSED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        SAVER.saved(),
+        RETRIES.dead_len()
+    );
+    result
 }
@@ -0,0 +1,59 @@
+// manifest.json lists every shard in an output dir, so downstream jobs don't have to
+// list the dir (& can tell finished shards from stray files)
+use crate::sink::write_file;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Shard {
+    // File name, relative to the manifest
+    pub name: String,
+    pub records: usize,
+    pub bytes: usize,
+    pub sha256: String,
+    // When the shard's first & last records were queued (ms since the epoch)
+    // None for records queued by versions w/o timestamps
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+    // Hash of the config & label map the shard was written w/
+    pub config_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Shards {
+    shards: Vec<Shard>,
+}
+
+pub struct Manifest {
+    path: PathBuf,
+    shards: Shards,
+}
+
+pub fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+impl Manifest {
+    pub fn open(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST);
+        let shards = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            Shards::default()
+        };
+        Ok(Manifest { path, shards })
+    }
+
+    // Rewrites the manifest w/ the shard added, readers see the old or new manifest
+    // but never half of one
+    // A shard that's rewritten (after a crash) replaces its old entry
+    pub async fn add(&mut self, shard: Shard) -> Result<()> {
+        self.shards.shards.retain(|s| s.name != shard.name);
+        self.shards.shards.push(shard);
+        write_file(&self.path, &serde_json::to_vec_pretty(&self.shards)?).await
+    }
+}
@@ -0,0 +1,95 @@
+// Matching on query params, for sites where a page's identity lives in the query string
+// (e.g. Yelp's user_details?userid=...)
+use crate::globals::QUERY_VALUE_RE;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use url::{form_urlencoded, Url};
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct QueryMatch {
+    // Keys that must be present
+    pub required: Vec<String>,
+    // Keys that must not be present
+    pub denied: Vec<String>,
+    // If set, every other key is dropped before the url is enqueued
+    pub allowed: Option<Vec<String>>,
+    // key -> regex, the value of the key (if present) must match
+    pub values: BTreeMap<String, String>,
+}
+
+impl QueryMatch {
+    pub fn matches(&self, url: &Url) -> bool {
+        let pairs: Vec<_> = url.query_pairs().collect();
+        let has = |key: &String| pairs.iter().any(|(k, _)| k == key);
+        if !self.required.iter().all(has) || self.denied.iter().any(has) {
+            return false;
+        }
+        pairs
+            .iter()
+            .all(|(k, v)| match self.values.get(k.as_ref()) {
+                Some(re) => QUERY_VALUE_RE.get(re).unwrap().is_match(v),
+                None => true,
+            })
+    }
+}
+
+// Lets `edit` drop & reorder the query's params, given as (decoded key, raw `key=value`)
+// Params are kept as written, since re-encoding them (e.g. `a` -> `a=`, `/` -> `%2F`) changes
+// the request the server sees. The query is only rewritten if `edit` changed something
+pub(crate) fn edit_params<F>(url: &mut Url, edit: F)
+where
+    F: FnOnce(&mut Vec<(String, &str)>),
+{
+    let query = match url.query() {
+        Some(q) => q.to_string(),
+        None => return,
+    };
+    let original: Vec<&str> = query.split('&').collect();
+    let mut params: Vec<(String, &str)> = original
+        .iter()
+        .map(|raw| {
+            let key = form_urlencoded::parse(raw.as_bytes())
+                .next()
+                .map(|(k, _)| k.into_owned())
+                .unwrap_or_default();
+            (key, *raw)
+        })
+        .collect();
+    edit(&mut params);
+    let raw: Vec<&str> = params.iter().map(|(_, raw)| *raw).collect();
+    if raw == original {
+        return;
+    }
+    if raw.is_empty() {
+        url.set_query(None);
+    } else {
+        url.set_query(Some(&raw.join("&")));
+    }
+}
+
+// Drops every query param not in `keep`
+pub fn retain_params(url: &Url, keep: &HashSet<&str>) -> Url {
+    let mut u = url.clone();
+    edit_params(&mut u, |params| {
+        params.retain(|(k, _)| keep.contains(k.as_str()))
+    });
+    u
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retains_params_as_written() {
+        let url = Url::parse("https://example.com/x?userid=a%2Fb&next=/y&flag&sort=1").unwrap();
+        let keep = ["userid", "flag"].iter().copied().collect();
+        assert_eq!(
+            retain_params(&url, &keep).as_str(),
+            "https://example.com/x?userid=a%2Fb&flag"
+        );
+        let keep = HashSet::new();
+        assert_eq!(retain_params(&url, &keep).as_str(), "https://example.com/x");
+    }
+}
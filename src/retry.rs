@@ -0,0 +1,207 @@
+// Retries transient failures w/ exponential backoff
+// URLs that run out of retries are moved to a dead-letter tree so they can be requeued by hand
+use crate::{
+    frontier::{now_ms, Entry, Frontier, Next},
+    globals::{DB, LIMITER},
+};
+use anyhow::{anyhow, Result};
+use log::trace;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+use url::Url;
+
+pub struct Config {
+    pub max_retries: u32,
+    pub base_ms: u64,
+    pub max_ms: u64,
+}
+
+// Returned by `fetch` for non 200 responses so the status can be inspected later
+#[derive(Debug)]
+pub struct StatusError(pub StatusCode);
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Received status code: {}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// Attached (as context) to errors that should stop the crawl instead of failing the url,
+// e.g. a page that couldn't be stored
+#[derive(Debug)]
+pub struct Fatal;
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fatal error, stopping the crawl")
+    }
+}
+
+// Timeouts, connection failures, 429 & 5xx are worth retrying, everything else isn't
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(StatusError(status)) = e.downcast_ref() {
+        return *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+    }
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout() || e.is_connect();
+    }
+    false
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Dead {
+    pub entry: Entry,
+    pub error: String,
+}
+
+pub struct Retries {
+    // Keyed by (due time in ms, id) so the first entry is always the next one due
+    pending: Tree,
+    dead: Tree,
+    config: Config,
+}
+
+impl Retries {
+    pub fn new(config: Config) -> Retries {
+        Retries {
+            pending: DB.open_tree("retry_queue").unwrap(),
+            dead: DB.open_tree("dead_letter").unwrap(),
+            config,
+        }
+    }
+
+    fn backoff_ms(&self, attempts: u32) -> u64 {
+        // attempts >= 1, cap the shift so it can't overflow
+        let factor = 1u64 << (attempts - 1).min(32);
+        self.config
+            .base_ms
+            .saturating_mul(factor)
+            .min(self.config.max_ms)
+    }
+
+    // Counts the failure against `entry`
+    // Returns false if the url ran out of retries & was moved to the dead-letter tree
+    pub fn schedule(&self, frontier: &Frontier, entry: &Entry, e: &anyhow::Error) -> Result<bool> {
+        let id = DB.generate_id()?;
+        let mut entry = entry.clone();
+        entry.retries += 1;
+        if entry.retries > self.config.max_retries {
+            let dead = Dead {
+                entry,
+                error: format!("{:?}", e),
+            };
+            self.dead
+                .insert(id.to_be_bytes(), bincode::serialize(&dead)?)?;
+            return Ok(false);
+        }
+        let due = now_ms() + self.backoff_ms(entry.retries);
+        trace!(
+            "Retrying: {} at: {} (attempt {})",
+            entry.url,
+            due,
+            entry.retries
+        );
+        let mut key = due.to_be_bytes().to_vec();
+        key.extend_from_slice(&id.to_be_bytes());
+        self.pending.insert(key, bincode::serialize(&entry)?)?;
+        frontier.retrying();
+        Ok(true)
+    }
+
+    // Leases the first url whose backoff has elapsed & whose host can be fetched from now,
+    // reserving the host's next request slot like `Frontier::lease`
+    // Otherwise waits until the next retry is due (or a due one's host is ready)
+    pub async fn lease_due(&self, frontier: &Frontier) -> Result<Next> {
+        let now = now_ms();
+        let mut wait: Option<Duration> = None;
+        let mut wait_for = |d: Duration| wait = Some(wait.map_or(d, |w| w.min(d)));
+        // Hosts that are rate limited or full stay that way for the rest of the pass
+        let mut busy = HashSet::new();
+        for kv in self.pending.range(..(now + 1).to_be_bytes()) {
+            let (k, v) = kv?;
+            let entry: Entry = bincode::deserialize(&v)?;
+            let url = Url::parse(&entry.url)?;
+            let host = url
+                .host_str()
+                .ok_or(anyhow!("No host in url: {:?}", entry.url))?;
+            if busy.contains(host) {
+                continue;
+            }
+            let permit = match LIMITER.try_acquire(host).await {
+                Ok(p) => p,
+                Err(Some(at)) => {
+                    wait_for(at.saturating_duration_since(Instant::now()));
+                    busy.insert(host.to_string());
+                    continue;
+                }
+                // Acking one of the host's leases notifies us
+                Err(None) => {
+                    busy.insert(host.to_string());
+                    continue;
+                }
+            };
+            // Another worker might have taken it first
+            if let Some(mut lease) = frontier.take(&self.pending, &k, entry)? {
+                lease.permit = Some(permit);
+                return Ok(Next::Ready(lease));
+            }
+        }
+        if let Some((k, _)) = self
+            .pending
+            .range((now + 1).to_be_bytes()..)
+            .next()
+            .transpose()?
+        {
+            let due = u64::from_be_bytes(k[..8].try_into()?);
+            wait_for(Duration::from_millis(due - now));
+        }
+        Ok(match wait {
+            Some(d) => Next::Wait(d),
+            None => Next::Empty,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn dead_len(&self) -> usize {
+        self.dead.len()
+    }
+
+    pub fn dead(&self) -> Result<Vec<Dead>> {
+        let mut out = vec![];
+        for kv in self.dead.iter() {
+            let (_, v) = kv?;
+            out.push(bincode::deserialize(&v)?);
+        }
+        Ok(out)
+    }
+
+    // Moves every dead url back into `frontier` w/ a fresh retry count
+    pub fn requeue_dead(&self, frontier: &Frontier) -> Result<usize> {
+        let mut count = 0;
+        for kv in self.dead.iter() {
+            let (k, v) = kv?;
+            let mut dead: Dead = bincode::deserialize(&v)?;
+            dead.entry.retries = 0;
+            // Insert before removing so a crash can't lose the url
+            frontier.push(&dead.entry)?;
+            self.dead.remove(k)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
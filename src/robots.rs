@@ -0,0 +1,402 @@
+// robots.txt fetching, parsing & enforcement
+// Parsed rules are cached per host in memory & in sled so restarts don't refetch them
+use crate::globals::{DB, LIMITER};
+use crate::warc;
+use anyhow::Result;
+use log::{info, trace, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+use url::Url;
+
+pub struct Config {
+    // Our product token (e.g. "examplebot"), matched against the User-agent lines of robots.txt
+    pub product_token: String,
+    // How long cached rules are trusted before refetching
+    pub ttl_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+// The rules from a robots.txt that apply to our user-agent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+    expires_at: u64,
+}
+
+// Server errors & network failures disallow the host, but only for a short while
+const ERROR_TTL_SECS: u64 = 300;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Supports the `*` wildcard & the `$` end anchor, otherwise patterns are prefixes
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let parts: Vec<_> = pattern.split('*').collect();
+    let mut rest = match path.strip_prefix(parts[0]) {
+        Some(r) => r,
+        None => return false,
+    };
+    if parts.len() == 1 {
+        return !anchored || rest.is_empty();
+    }
+    let last = parts.len() - 1;
+    for (i, part) in parts.iter().enumerate().skip(1) {
+        if i == last && anchored {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+impl Rules {
+    fn allow_all() -> Rules {
+        Rules {
+            rules: vec![],
+            crawl_delay: None,
+            expires_at: 0,
+        }
+    }
+
+    fn disallow_all() -> Rules {
+        Rules {
+            rules: vec![Rule {
+                allow: false,
+                pattern: "/".to_string(),
+            }],
+            crawl_delay: None,
+            expires_at: 0,
+        }
+    }
+
+    fn parse(txt: &str, product_token: &str) -> Rules {
+        // (user agents, rules, crawl delay)
+        let mut groups: Vec<(Vec<String>, Vec<Rule>, Option<f64>)> = vec![];
+        // Consecutive User-agent lines share a group
+        let mut in_agents = false;
+        for line in txt.lines() {
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line,
+            };
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim().to_lowercase(), v.trim()),
+                None => continue,
+            };
+            if key == "user-agent" {
+                if !in_agents {
+                    groups.push((vec![], vec![], None));
+                    in_agents = true;
+                }
+                groups.last_mut().unwrap().0.push(value.to_lowercase());
+                continue;
+            }
+            in_agents = false;
+            let group = match groups.last_mut() {
+                Some(g) => g,
+                // Rules before any User-agent line are invalid
+                None => continue,
+            };
+            match key.as_str() {
+                // An empty Disallow allows everything
+                "allow" | "disallow" if !value.is_empty() => group.1.push(Rule {
+                    allow: key == "allow",
+                    pattern: value.to_string(),
+                }),
+                "crawl-delay" => group.2 = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        // Use the groups for our product token (compared case insensitively, as per RFC 9309),
+        // falling back to "*"
+        let token = product_token.to_lowercase();
+        let specificity = |agent: &String| -> Option<usize> {
+            // Some robots.txt files list versions, e.g. "examplebot/1.0"
+            let agent = agent.split('/').next().unwrap_or_default().trim();
+            if agent == "*" {
+                Some(0)
+            } else if agent == token {
+                Some(1)
+            } else {
+                None
+            }
+        };
+        let best = groups
+            .iter()
+            .filter_map(|(agents, _, _)| agents.iter().filter_map(specificity).max())
+            .max();
+        let mut out = Rules::allow_all();
+        if let Some(best) = best {
+            for (agents, rules, delay) in groups {
+                if agents.iter().filter_map(specificity).any(|s| s == best) {
+                    out.rules.extend(rules);
+                    out.crawl_delay = out.crawl_delay.or(delay);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn allowed(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_string(),
+        };
+        // The longest matching pattern wins, Allow wins ties
+        let mut best: Option<(usize, bool)> = None;
+        for rule in &self.rules {
+            if !pattern_matches(&rule.pattern, &path) {
+                continue;
+            }
+            best = best.max(Some((rule.pattern.len(), rule.allow)));
+        }
+        match best {
+            Some((_, allow)) => allow,
+            None => true,
+        }
+    }
+}
+
+// Locked while fetching so only 1 worker fetches a given robots.txt
+type Slot = Arc<Mutex<Option<Arc<Rules>>>>;
+
+pub struct Robots {
+    hosts: Mutex<HashMap<String, Slot>>,
+    cache: Tree,
+    config: Config,
+}
+
+impl Robots {
+    pub fn new(config: Config) -> Robots {
+        Robots {
+            hosts: Mutex::new(HashMap::new()),
+            cache: DB.open_tree("robots").unwrap(),
+            config,
+        }
+    }
+
+    fn is_fresh(&self, rules: &Rules) -> bool {
+        now_secs() < rules.expires_at
+    }
+
+    async fn fetch(&self, robots_url: &Url) -> Rules {
+        let (mut rules, ttl) = match self.download(robots_url).await {
+            Some(rules) => (rules, self.config.ttl_secs),
+            None => (Rules::disallow_all(), ERROR_TTL_SECS),
+        };
+        rules.expires_at = now_secs() + ttl;
+        rules
+    }
+
+    // None if the host is unreachable or erroring
+    async fn download(&self, robots_url: &Url) -> Option<Rules> {
+        let host = robots_url.host_str().unwrap_or_default();
+        let _permit = LIMITER.acquire(host).await;
+        let resp = match warc::get(robots_url).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Failed to fetch: {} with error: {:?}", robots_url, e);
+                return None;
+            }
+        };
+        let status = resp.status;
+        if status.is_success() {
+            let txt = String::from_utf8_lossy(&resp.body);
+            Some(Rules::parse(&txt, &self.config.product_token))
+        } else if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            // No robots.txt (or it's forbidden), so everything is allowed
+            // A 429 is the server erroring (like a 5xx), not a missing robots.txt
+            Some(Rules::allow_all())
+        } else {
+            warn!("Received status code: {} for {}", status, robots_url);
+            None
+        }
+    }
+
+    async fn rules(&self, url: &Url) -> Result<Arc<Rules>> {
+        let origin = url.origin().ascii_serialization();
+        let slot = {
+            let mut hosts = self.hosts.lock().await;
+            hosts.entry(origin.clone()).or_default().clone()
+        };
+        let mut slot = slot.lock().await;
+        if let Some(rules) = slot.as_ref() {
+            if self.is_fresh(rules) {
+                return Ok(rules.clone());
+            }
+        }
+
+        let cached = match self.cache.get(origin.as_bytes())? {
+            Some(bytes) => Some(bincode::deserialize::<Rules>(&bytes)?),
+            None => None,
+        };
+        let rules = match cached {
+            Some(r) if self.is_fresh(&r) => r,
+            _ => {
+                let robots_url = Url::parse(&origin)?.join("/robots.txt")?;
+                trace!("Fetching: {}", robots_url);
+                let rules = self.fetch(&robots_url).await;
+                info!(
+                    "Loaded {} robots.txt rule(s) for: {}",
+                    rules.rules.len(),
+                    origin
+                );
+                self.cache
+                    .insert(origin.as_bytes(), bincode::serialize(&rules)?)?;
+                rules
+            }
+        };
+        if let (Some(delay), Some(host)) = (rules.crawl_delay, url.host_str()) {
+            if delay.is_finite() && delay > 0.0 {
+                LIMITER
+                    .set_min_delay(host, Duration::from_secs_f64(delay))
+                    .await;
+            }
+        }
+        let rules = Arc::new(rules);
+        *slot = Some(rules.clone());
+        Ok(rules)
+    }
+
+    pub async fn allowed(&self, url: &Url) -> Result<bool> {
+        Ok(self.rules(url).await?.allowed(url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(rules: &Rules, path: &str) -> bool {
+        rules.allowed(
+            &Url::parse("https://example.com")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("/a", "/a/b"));
+        assert!(!pattern_matches("/a", "/b/a"));
+        assert!(pattern_matches("/*.php", "/x/index.php?y=1"));
+        assert!(pattern_matches("/*.php$", "/x/index.php"));
+        assert!(!pattern_matches("/*.php$", "/x/index.php?y=1"));
+        assert!(pattern_matches("/a$", "/a"));
+        assert!(!pattern_matches("/a$", "/ab"));
+        assert!(pattern_matches("/a*b*c", "/a-b-c-d"));
+        assert!(!pattern_matches("/a*c*b", "/a-b-c"));
+    }
+
+    #[test]
+    fn picks_our_group() {
+        let txt = "
+User-agent: *
+Disallow: /
+
+User-agent: examplebot
+User-agent: otherbot
+Disallow: /private # comment
+Crawl-delay: 2
+
+User-agent: example
+Disallow: /other
+";
+        let rules = Rules::parse(txt, "ExampleBot");
+        assert!(allowed(&rules, "/"));
+        assert!(allowed(&rules, "/other"));
+        assert!(!allowed(&rules, "/private/x"));
+        assert_eq!(rules.crawl_delay, Some(2.0));
+
+        let rules = Rules::parse(txt, "SomeoneElse");
+        assert!(!allowed(&rules, "/"));
+        assert_eq!(rules.crawl_delay, None);
+    }
+
+    #[test]
+    fn tokens_match_exactly() {
+        let txt = "
+User-agent: Mozilla
+Disallow: /
+
+User-agent: ExampleBot/2.1
+Disallow: /private
+";
+        // Not a substring match on the token
+        let rules = Rules::parse(txt, "moz");
+        assert!(allowed(&rules, "/x"));
+        let rules = Rules::parse(txt, "mozilla");
+        assert!(!allowed(&rules, "/x"));
+        // Versions in the robots.txt are ignored
+        let rules = Rules::parse(txt, "examplebot");
+        assert!(allowed(&rules, "/x"));
+        assert!(!allowed(&rules, "/private"));
+    }
+
+    #[test]
+    fn no_matching_group_allows_everything() {
+        let rules = Rules::parse("User-agent: otherbot\nDisallow: /", "examplebot");
+        assert!(allowed(&rules, "/x"));
+        // Rules before a User-agent line are ignored
+        let rules = Rules::parse("Disallow: /\nUser-agent: *\nDisallow:", "examplebot");
+        assert!(allowed(&rules, "/x"));
+    }
+
+    #[test]
+    fn longest_match_wins() {
+        let txt = "
+User-agent: *
+Disallow: /a
+Allow: /a/b
+Disallow: /a/b/c
+Disallow: /*.gif$
+";
+        let rules = Rules::parse(txt, "examplebot");
+        assert!(allowed(&rules, "/x"));
+        assert!(!allowed(&rules, "/a/x"));
+        assert!(allowed(&rules, "/a/b/x"));
+        assert!(!allowed(&rules, "/a/b/c"));
+        assert!(!allowed(&rules, "/a/b/x.gif"));
+        assert!(allowed(&rules, "/a/b/x.gif?y"));
+    }
+
+    #[test]
+    fn allow_wins_ties() {
+        let rules = Rules::parse("User-agent: *\nDisallow: /page\nAllow: /page", "bot");
+        assert!(allowed(&rules, "/page"));
+        let rules = Rules::parse("User-agent: *\nAllow: /page\nDisallow: /page", "bot");
+        assert!(allowed(&rules, "/page"));
+    }
+
+    #[test]
+    fn matches_query() {
+        let rules = Rules::parse("User-agent: *\nDisallow: /*?sort=", "bot");
+        assert!(allowed(&rules, "/list"));
+        assert!(!allowed(&rules, "/list?sort=asc"));
+    }
+}
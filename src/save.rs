@@ -1,11 +1,16 @@
+use crate::frontier::now_ms;
 use crate::globals::DB;
-use anyhow::Result;
-use bincode;
-use log::{info, trace};
-use serde::{de::DeserializeOwned, ser::Serialize};
-use sled::{self, Tree};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::{self, time::Duration};
+use crate::sink::{Chunk, Sink};
+use anyhow::{anyhow, Result};
+use fasthash::metro::hash64;
+use log::info;
+use serde::{de::DeserializeOwned, ser::Serialize, Deserialize};
+use sled::{self, transaction::TransactionError, Transactional, Tree};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::sleep;
 
 // The saver uses Bincode for its internal storage
 // This prevents untagged types from being used
@@ -15,74 +20,382 @@ use tokio::{self, time::Duration};
 // & change serialization format in the lambda)
 // 2. (hack, chosen) pre-serialize to JSON & pass in a string
 
+// Queued records are keyed by (ms since the epoch, id) so the age of the oldest one is known
+// Keys from before that were just the id
+const LEGACY_KEY_LEN: usize = 8;
+
+// ms since the epoch, None for legacy keys
+fn queued_at(key: &[u8]) -> Option<u64> {
+    if key.len() == LEGACY_KEY_LEN {
+        return None;
+    }
+    Some(u64::from_be_bytes(key[..8].try_into().unwrap()))
+}
+
 pub struct Config {
-    pub check_interval_secs: u64,
     pub chunk_size: usize,
-    pub start_chunk: usize,
+    // A chunk is also cut once it's this many bytes (before the sink encodes it)
+    pub max_bytes: Option<usize>,
+    // or once its oldest record has waited this long
+    pub max_age: Option<Duration>,
+    // Records are split by the hash of their key, not split if empty
+    pub splits: Vec<SplitConfig>,
 }
 
-pub struct Saver<T: Serialize + DeserializeOwned> {
+#[derive(Deserialize, Debug, Clone)]
+pub struct SplitConfig {
+    pub name: String,
+    // Relative to the other splits' ratios
+    pub ratio: f64,
+}
+
+// The sinks of a split (None if records aren't split) & the number of its first chunk if
+// none was stored (i.e. output from older versions)
+pub type Open<'a, T> = &'a dyn Fn(Option<&str>) -> (Vec<Box<dyn Sink<T>>>, usize);
+
+// Each split's upper bound of the hash space, in the order of `splits`
+fn assign_splits(splits: &[SplitConfig]) -> Vec<(f64, usize)> {
+    let total: f64 = splits.iter().map(|s| s.ratio).sum();
+    let mut bound = 0.0;
+    let mut assign = vec![];
+    for (i, s) in splits.iter().enumerate() {
+        assert!(s.ratio > 0.0, "Split: {} needs a positive ratio", s.name);
+        bound += s.ratio / total;
+        assign.push((bound, i));
+    }
+    assign
+}
+
+// Deterministic, so a key is always in the same split (as long as the ratios don't change)
+// 0 (the only split) if records aren't split
+fn split_index(assign: &[(f64, usize)], key: &str) -> usize {
+    if assign.is_empty() {
+        return 0;
+    }
+    let x = hash64(key) as f64 / u64::MAX as f64;
+    assign
+        .iter()
+        .find(|(bound, _)| x < *bound)
+        // Rounding can leave the last bound just under 1
+        .unwrap_or(assign.last().unwrap())
+        .1
+}
+
+// Locked while a chunk is written so chunks are numbered & written in order
+struct Output<T> {
+    next_chunk: usize,
+    sinks: Vec<Box<dyn Sink<T>>>,
+}
+
+// Each split has its own queue, chunk sequence & sinks
+struct Split<T> {
+    name: Option<String>,
+    queue: Tree,
     // This is eventually consistent with the actual queue length
     queue_len: AtomicUsize,
-    //db: Db,
-    queue: Tree,
-    // TODO: This should return a future since everything is async anyway
-    // but that is hard
-    save: fn(usize, &Vec<T>) -> Result<()>,
-    config: Config,
+    // Same for the size of the queued records
+    queue_bytes: AtomicUsize,
+    // Where the chunk sequence is stored in the state tree, so chunk numbers are never
+    // reused no matter what happens to the output dirs
+    next_chunk_key: Vec<u8>,
+    output: Mutex<Output<T>>,
 }
 
-impl<T: Serialize + DeserializeOwned> Saver<T> {
-    pub fn new(
-        /*db: Db,*/ save: fn(usize, &Vec<T>) -> Result<()>,
-        config: Config,
-    ) -> Saver<T> {
-        let queue = DB.open_tree("saved_data").unwrap();
+impl<T: Serialize + DeserializeOwned + Send + Sync> Split<T> {
+    fn open(name: Option<&str>, state: &Tree, open: Open<T>) -> Split<T> {
+        let (queue_name, next_chunk_key) = match name {
+            Some(n) => (format!("saved_data/{}", n), format!("next_chunk/{}", n)),
+            None => ("saved_data".to_string(), "next_chunk".to_string()),
+        };
+        let queue = DB.open_tree(queue_name).unwrap();
         let queue_len = queue.len();
-        Saver {
-            queue_len: AtomicUsize::new(queue_len),
+        let queue_bytes = queue.iter().values().map(|v| v.unwrap().len()).sum();
+        let (sinks, start_chunk) = open(name);
+        let next_chunk = match state.get(&next_chunk_key).unwrap() {
+            Some(v) => u64::from_be_bytes(v.as_ref().try_into().unwrap()) as usize,
+            None => start_chunk,
+        };
+        Split {
+            name: name.map(String::from),
             queue,
-            save,
+            queue_len: AtomicUsize::new(queue_len),
+            queue_bytes: AtomicUsize::new(queue_bytes),
+            next_chunk_key: next_chunk_key.into_bytes(),
+            output: Mutex::new(Output { next_chunk, sinks }),
+        }
+    }
+
+    fn is_full(&self, config: &Config) -> bool {
+        self.queue_len.load(Ordering::Relaxed) >= config.chunk_size
+            || config
+                .max_bytes
+                .is_some_and(|max| self.queue_bytes.load(Ordering::Relaxed) >= max)
+    }
+
+    // How long the oldest pending record has been waiting
+    fn oldest_age(&self) -> Result<Option<Duration>> {
+        Ok(self.queue.first()?.map(|(k, _)| match queued_at(&k) {
+            Some(at) => Duration::from_millis(now_ms().saturating_sub(at)),
+            // No idea, but it's from a previous run so it's old enough
+            None => Duration::from_millis(u64::MAX),
+        }))
+    }
+
+    // Writes a chunk of up to `size` records (& `max_bytes`), returns how many were written
+    async fn write_chunk(&self, state: &Tree, config: &Config, size: usize) -> Result<usize> {
+        let mut output = self.output.lock().await;
+        // Another write might have happened while we waited for the lock
+        let size = size.min(self.queue_len.load(Ordering::Relaxed));
+        if size == 0 {
+            return Ok(0);
+        }
+        // Records are only removed from the queue once every sink has written them, so a
+        // crash mid-write means the chunk is written again (w/ the same number) on restart
+        let mut xs: Vec<T> = vec![];
+        let mut keys = vec![];
+        let mut bytes = 0;
+        let mut records = self.queue.iter();
+        for i in 0..size {
+            match records.next().transpose()? {
+                Some((k, v)) => {
+                    // Stop before the record that would take the chunk past max_bytes, unless
+                    // it's the first one (a record bigger than that is a chunk of its own)
+                    let too_big = config.max_bytes.is_some_and(|max| bytes + v.len() > max);
+                    if too_big && !xs.is_empty() {
+                        break;
+                    }
+                    bytes += v.len();
+                    xs.push(bincode::deserialize(&v)?);
+                    keys.push(k);
+                }
+                None => {
+                    // We could just ignore this & break from the loop, but this indicates
+                    // a bug in our code
+                    panic!(
+                        "Tried to load chunk of size: {}, but only found: {} elements",
+                        size, i
+                    );
+                }
+            };
+        }
+        let size = xs.len();
+        let times: Vec<_> = keys.iter().filter_map(|k| queued_at(k)).collect();
+
+        let chunk = Chunk {
+            idx: output.next_chunk,
+            records: xs,
+            start_ms: times.iter().min().copied(),
+            end_ms: times.iter().max().copied(),
+        };
+        for sink in output.sinks.iter_mut() {
+            sink.write_chunk(&chunk).await?;
+        }
+
+        let next = (output.next_chunk + 1) as u64;
+        (&self.queue, state)
+            .transaction(|(queue_tx, state_tx)| {
+                for k in &keys {
+                    queue_tx.remove(k)?;
+                }
+                state_tx.insert(self.next_chunk_key.as_slice(), &next.to_be_bytes())?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| anyhow!("Failed to remove saved chunk: {:?}", e))?;
+        output.next_chunk += 1;
+        self.queue_len.fetch_sub(size, Ordering::Relaxed);
+        self.queue_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        Ok(size)
+    }
+}
+
+pub struct Saver<T: Serialize + DeserializeOwned> {
+    splits: Vec<Split<T>>,
+    // (upper bound of the split's share of the hash space, index into `splits`)
+    // Empty if records aren't split
+    assign: Vec<(f64, usize)>,
+    state: Tree,
+    config: Config,
+    // Signalled once a full chunk is waiting (or the saver is closed)
+    notify: Notify,
+    closed: AtomicBool,
+    // Records written so far by this process
+    saved: AtomicUsize,
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync> Saver<T> {
+    pub fn new(/*db: Db,*/ open: Open<T>, config: Config) -> Saver<T> {
+        let state = DB.open_tree("saver_state").unwrap();
+        let assign = assign_splits(&config.splits);
+        let mut splits: Vec<_> = config
+            .splits
+            .iter()
+            .map(|s| Split::open(Some(&s.name), &state, open))
+            .collect();
+        // Records queued before splits were configured are still written, unsplit
+        let unsplit = Split::open(None, &state, open);
+        if assign.is_empty() || unsplit.queue_len.load(Ordering::Relaxed) > 0 {
+            splits.push(unsplit);
+        }
+        Saver {
+            splits,
+            assign,
+            state,
             config,
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            saved: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn saved(&self) -> usize {
+        self.saved.load(Ordering::Relaxed)
+    }
+
+    fn split_of(&self, key: &str) -> &Split<T> {
+        &self.splits[split_index(&self.assign, key)]
+    }
+
+    async fn write_chunk(&self, split: &Split<T>, size: usize) -> Result<usize> {
+        let n = split.write_chunk(&self.state, &self.config, size).await?;
+        self.saved.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    // Writes everything that's pending, the last chunk can be smaller than `chunk_size`
+    pub async fn flush(&self) -> Result<()> {
+        for split in &self.splits {
+            while self.write_chunk(split, self.config.chunk_size).await? > 0 {}
+            for sink in split.output.lock().await.sinks.iter_mut() {
+                sink.flush().await?;
+            }
         }
+        Ok(())
     }
 
-    pub fn add(&self, x: T) {
+    // Stops `run`, writes whatever is left & closes the sinks
+    pub async fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify.notify_one();
+        self.flush().await?;
+        for split in &self.splits {
+            for sink in split.output.lock().await.sinks.iter_mut() {
+                sink.close().await?;
+            }
+        }
+        Ok(())
+    }
+
+    // `key` decides the record's split (e.g. the canonical url)
+    pub fn add(&self, key: &str, x: T) {
+        let split = self.split_of(key);
         let id = DB.generate_id().unwrap();
+        let mut key = now_ms().to_be_bytes().to_vec();
+        key.extend_from_slice(&id.to_be_bytes());
         let bytes = bincode::serialize(&x).unwrap();
-        self.queue.insert(id.to_be_bytes(), bytes).unwrap();
-        self.queue_len.fetch_add(1, Ordering::Relaxed);
+        split.queue_bytes.fetch_add(bytes.len(), Ordering::Relaxed);
+        split.queue.insert(key, bytes).unwrap();
+        let len = split.queue_len.fetch_add(1, Ordering::Relaxed) + 1;
+        // The first pending record starts the max_age timer
+        if len == 1 || split.is_full(&self.config) {
+            self.notify.notify_one();
+        }
     }
 
     pub async fn run(&self) -> Result<()> {
         let chunk_size = self.config.chunk_size;
-        let mut chunks = self.config.start_chunk;
         loop {
-            let count = self.queue_len.load(Ordering::Relaxed);
-            if count >= chunk_size {
-                self.queue_len.fetch_sub(chunk_size, Ordering::Relaxed);
-                let mut xs: Vec<T> = vec![];
-                for i in 0..chunk_size {
-                    match self.queue.pop_min()? {
-                        Some((_, v)) => xs.push(bincode::deserialize(&v)?),
-                        None => {
-                            // We could just ignore this & break from the loop, but this indicates
-                            // a bug in our code
-                            panic!(
-                                "Tried to load chunk of size: {}, but only found: {} elements",
-                                chunk_size, i
-                            );
-                        }
-                    };
+            if self.closed.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let mut wrote = false;
+            let mut expires_in: Option<Duration> = None;
+            for split in &self.splits {
+                if split.is_full(&self.config) {
+                    self.write_chunk(split, chunk_size).await?;
+                    wrote = true;
+                    continue;
                 }
-
-                (self.save)(chunks, &xs)?;
-                chunks += 1;
-            } else {
-                info!("Nothing found to save, sleeping...");
-                tokio::time::sleep(Duration::from_secs(self.config.check_interval_secs)).await;
+                let count = split.queue_len.load(Ordering::Relaxed);
+                match (self.config.max_age, split.oldest_age()?) {
+                    (Some(max), Some(age)) if age >= max && count > 0 => {
+                        info!(
+                            "Records waited for over {:?}, saving a partial chunk (split: {:?})",
+                            max, split.name
+                        );
+                        self.write_chunk(split, count).await?;
+                        wrote = true;
+                    }
+                    (Some(max), Some(age)) => {
+                        let t = max.saturating_sub(age);
+                        expires_in = Some(expires_in.map_or(t, |e| e.min(t)));
+                    }
+                    _ => {}
+                }
+            }
+            if wrote {
                 continue;
             }
+            info!("Nothing found to save, waiting...");
+            match expires_in {
+                Some(t) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {}
+                        _ = sleep(t) => {}
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn splits(ratios: &[(&str, f64)]) -> Vec<SplitConfig> {
+        ratios
+            .iter()
+            .map(|(name, ratio)| SplitConfig {
+                name: name.to_string(),
+                ratio: *ratio,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unsplit() {
+        assert_eq!(split_index(&[], "https://example.com/a"), 0);
+    }
+
+    #[test]
+    fn split_by_ratio() {
+        let assign = assign_splits(&splits(&[("train", 8.0), ("val", 1.0), ("test", 1.0)]));
+        assert_eq!(assign.last().unwrap().1, 2);
+        let mut counts = [0; 3];
+        for i in 0..10_000 {
+            let key = format!("https://example.com/user_details?userid={}", i);
+            let idx = split_index(&assign, &key);
+            // Always the same split
+            assert_eq!(split_index(&assign, &key), idx);
+            counts[idx] += 1;
         }
+        assert!((7_600..8_400).contains(&counts[0]), "{:?}", counts);
+        assert!((800..1_200).contains(&counts[1]), "{:?}", counts);
+        assert!((800..1_200).contains(&counts[2]), "{:?}", counts);
+    }
+
+    #[test]
+    fn single_split_gets_everything() {
+        let assign = assign_splits(&splits(&[("all", 0.3)]));
+        for i in 0..100 {
+            assert_eq!(split_index(&assign, &i.to_string()), 0);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_zero_ratio() {
+        assign_splits(&splits(&[("train", 1.0), ("val", 0.0)]));
     }
 }
@@ -0,0 +1,376 @@
+// Where the saver's chunks end up, each chunk is written to every configured sink
+use crate::globals::{Save, SelectorValue};
+use crate::manifest::{self, Manifest, Shard};
+use anyhow::Result;
+use arrow::array::{ArrayRef, ListBuilder, StringBuilder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression as GzLevel};
+use log::trace;
+use parquet::{
+    arrow::ArrowWriter, basic::Compression as ParquetCompression,
+    file::properties::WriterProperties,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+pub struct Chunk<T> {
+    // Sequence number, never reused
+    pub idx: usize,
+    pub records: Vec<T>,
+    // When the first & last records were queued (ms since the epoch)
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+}
+
+#[async_trait]
+pub trait Sink<T>: Send + Sync {
+    async fn write_chunk(&mut self, chunk: &Chunk<T>) -> Result<()>;
+    // Makes everything written so far durable
+    async fn flush(&mut self) -> Result<()>;
+    // Nothing is written after this
+    async fn close(&mut self) -> Result<()>;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    // One `[a, b, c]` file per chunk
+    #[default]
+    JsonArray,
+    // One record per line
+    Jsonl,
+    #[serde(rename = "jsonl+gzip")]
+    JsonlGzip,
+    #[serde(rename = "jsonl+zstd")]
+    JsonlZstd,
+    // One file per chunk, w/ the chunk as a single row group
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    // CPU bound, so it's done on a blocking thread
+    pub(crate) async fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Gzip => Ok(tokio::task::spawn_blocking(move || {
+                let mut enc = GzEncoder::new(vec![], GzLevel::default());
+                enc.write_all(&bytes)?;
+                enc.finish()
+            })
+            .await??),
+            Compression::Zstd => Ok(tokio::task::spawn_blocking(move || {
+                zstd::encode_all(bytes.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL)
+            })
+            .await??),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    pub format: Format,
+    // Output directory
+    pub path: String,
+}
+
+impl Config {
+    // Each split is written to a subdirectory
+    pub fn split(&self, name: Option<&str>) -> Config {
+        let path = match name {
+            Some(n) => Path::new(&self.path).join(n).to_string_lossy().into_owned(),
+            None => self.path.clone(),
+        };
+        Config {
+            format: self.format,
+            path,
+        }
+    }
+
+    // The highest chunk number in the output dir (chunks are named "{idx}.{ext}")
+    // Only needed for dirs written before the chunk sequence was stored in the db
+    pub fn last_chunk(&self) -> usize {
+        std::fs::create_dir_all(&self.path).unwrap();
+        std::fs::read_dir(&self.path)
+            .unwrap()
+            .filter_map(|e| {
+                let name = e.ok()?.file_name();
+                name.to_str()?.split('.').next()?.parse().ok()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // `labels` is label name -> whether it's a list, for formats w/ a schema
+    pub fn open(
+        &self,
+        labels: &BTreeMap<String, bool>,
+        chunk_size: usize,
+        config_hash: &str,
+    ) -> Box<dyn Sink<String>> {
+        std::fs::create_dir_all(&self.path).unwrap();
+        let dir = Dir {
+            path: PathBuf::from(&self.path),
+            manifest: Manifest::open(Path::new(&self.path)).unwrap(),
+            config_hash: config_hash.to_string(),
+        };
+        let jsonl = |dir, compression| Box::new(JsonlSink { dir, compression });
+        match self.format {
+            Format::JsonArray => Box::new(JsonArraySink { dir }),
+            Format::Jsonl => jsonl(dir, Compression::None),
+            Format::JsonlGzip => jsonl(dir, Compression::Gzip),
+            Format::JsonlZstd => jsonl(dir, Compression::Zstd),
+            Format::Parquet => Box::new(ParquetSink::new(dir, labels, chunk_size)),
+        }
+    }
+}
+
+// An output dir & its manifest
+struct Dir {
+    path: PathBuf,
+    manifest: Manifest,
+    config_hash: String,
+}
+
+impl Dir {
+    async fn write<T: Sync>(&mut self, name: String, chunk: &Chunk<T>, bytes: &[u8]) -> Result<()> {
+        trace!("Saving chunk: {}...", name);
+        write_file(&self.path.join(&name), bytes).await?;
+        self.manifest
+            .add(Shard {
+                name,
+                records: chunk.records.len(),
+                bytes: bytes.len(),
+                sha256: manifest::sha256(bytes),
+                start_ms: chunk.start_ms,
+                end_ms: chunk.end_ms,
+                config_hash: self.config_hash.clone(),
+            })
+            .await
+    }
+}
+
+// Writes to a temp file that's fsynced & renamed into place, so `path` is either missing
+// or complete, even after a crash
+// An existing file is replaced, it's a chunk that was written before a crash but whose
+// records weren't removed from the queue yet
+// Temp names are unique so concurrent writes to the same path can't clobber each other
+pub(crate) async fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", Uuid::new_v4()));
+    let mut f = File::create(&tmp).await?;
+    f.write_all(bytes).await?;
+    f.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    // Makes the rename itself durable
+    if let Some(dir) = path.parent() {
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+// Records are pre-serialized JSON (see save.rs), so they're joined as is
+pub struct JsonArraySink {
+    dir: Dir,
+}
+
+#[async_trait]
+impl Sink<String> for JsonArraySink {
+    async fn write_chunk(&mut self, chunk: &Chunk<String>) -> Result<()> {
+        let json_arr = format!("[{}]", chunk.records.join(", "));
+        let name = format!("{}.json", chunk.idx);
+        self.dir.write(name, chunk, json_arr.as_bytes()).await
+    }
+
+    // Each chunk's file is complete once written
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// The records can't contain raw newlines since they're serialized w/o pretty printing
+pub struct JsonlSink {
+    dir: Dir,
+    compression: Compression,
+}
+
+#[async_trait]
+impl Sink<String> for JsonlSink {
+    async fn write_chunk(&mut self, chunk: &Chunk<String>) -> Result<()> {
+        let mut lines = String::new();
+        for x in &chunk.records {
+            lines.push_str(x);
+            lines.push('\n');
+        }
+        let bytes = self.compression.compress(lines.into_bytes()).await?;
+        let name = format!("{}.jsonl{}", chunk.idx, self.compression.extension());
+        self.dir.write(name, chunk, &bytes).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Columns are url, raw, raw_hash, input & one per label, labels are strings or lists of
+// strings. Labels a page's map doesn't have are null (as are raw & raw_hash, depending on
+// the raw_html option)
+pub struct ParquetSink {
+    dir: Dir,
+    schema: SchemaRef,
+    labels: Arc<Vec<(String, bool)>>,
+    row_group_size: usize,
+}
+
+impl ParquetSink {
+    fn new(dir: Dir, labels: &BTreeMap<String, bool>, row_group_size: usize) -> ParquetSink {
+        let mut fields = vec![
+            Field::new("url", DataType::Utf8, false),
+            Field::new("raw", DataType::Utf8, true),
+            Field::new("raw_hash", DataType::Utf8, true),
+            Field::new("input", DataType::Utf8, false),
+        ];
+        for (name, &is_list) in labels {
+            let data_type = if is_list {
+                // Matches what ListBuilder produces
+                DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)))
+            } else {
+                DataType::Utf8
+            };
+            fields.push(Field::new(name, data_type, true));
+        }
+        ParquetSink {
+            dir,
+            schema: Arc::new(Schema::new(fields)),
+            labels: Arc::new(labels.iter().map(|(k, v)| (k.clone(), *v)).collect()),
+            row_group_size,
+        }
+    }
+}
+
+fn encode_parquet(
+    schema: SchemaRef,
+    labels: &[(String, bool)],
+    row_group_size: usize,
+    xs: &[String],
+) -> Result<Vec<u8>> {
+    let saves = xs
+        .iter()
+        .map(|x| serde_json::from_str(x))
+        .collect::<Result<Vec<Save>, _>>()?;
+
+    let column = |f: fn(&Save) -> Option<&str>| {
+        let mut b = StringBuilder::new();
+        for s in &saves {
+            b.append_option(f(s));
+        }
+        Arc::new(b.finish()) as ArrayRef
+    };
+    let mut columns = vec![
+        column(|s| Some(&s.url)),
+        column(|s| s.raw.as_deref()),
+        column(|s| s.raw_hash.as_deref()),
+        column(|s| Some(&s.input)),
+    ];
+    for (name, is_list) in labels {
+        if *is_list {
+            let mut b = ListBuilder::new(StringBuilder::new());
+            for s in &saves {
+                match s.labels.get(name) {
+                    Some(SelectorValue::Arr(v)) => {
+                        for x in v {
+                            b.values().append_value(x);
+                        }
+                        b.append(true);
+                    }
+                    // The label is a list in another map
+                    Some(SelectorValue::Str(v)) => {
+                        b.values().append_value(v);
+                        b.append(true);
+                    }
+                    None => b.append(false),
+                }
+            }
+            columns.push(Arc::new(b.finish()));
+        } else {
+            let mut b = StringBuilder::new();
+            for s in &saves {
+                match s.labels.get(name) {
+                    Some(SelectorValue::Str(v)) => b.append_value(v),
+                    // Only possible for records queued under an older label map
+                    Some(SelectorValue::Arr(v)) => b.append_value(serde_json::to_string(v)?),
+                    None => b.append_null(),
+                }
+            }
+            columns.push(Arc::new(b.finish()));
+        }
+    }
+
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    let props = WriterProperties::builder()
+        .set_max_row_group_size(row_group_size)
+        .set_compression(ParquetCompression::SNAPPY)
+        .build();
+    let mut buf = vec![];
+    let mut writer = ArrowWriter::try_new(&mut buf, schema, Some(props))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(buf)
+}
+
+#[async_trait]
+impl Sink<String> for ParquetSink {
+    async fn write_chunk(&mut self, chunk: &Chunk<String>) -> Result<()> {
+        let (schema, labels, row_group_size) = (
+            self.schema.clone(),
+            self.labels.clone(),
+            self.row_group_size,
+        );
+        let xs = chunk.records.clone();
+        // CPU bound, so it's done on a blocking thread
+        let bytes = tokio::task::spawn_blocking(move || {
+            encode_parquet(schema, &labels, row_group_size, &xs)
+        })
+        .await??;
+        let name = format!("{}.parquet", chunk.idx);
+        self.dir.write(name, chunk, &bytes).await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+// Records every fetched response (& the request for it) to WARC files, so pages can be
+// re-extracted later w/o refetching them
+// Each record is gzipped separately (as is standard for .warc.gz) & files are rotated once
+// they reach `max_bytes`. The file being written ends in .open until it's rotated or closed
+// The recorded response is the one reqwest hands us: de-chunked & decompressed, so the
+// headers describing the original encoding are renamed (as X-Archive-Orig-*) & Content-Length
+// is the recorded body's
+use crate::globals::{CLIENT, HEADERS, WARC};
+use crate::sink::Compression;
+use anyhow::{bail, Result};
+use log::{error, info};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING,
+        CONTENT_LENGTH, LOCATION, TRANSFER_ENCODING,
+    },
+    StatusCode, Version,
+};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use url::{Position, Url};
+use uuid::Uuid;
+
+pub struct Config {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+}
+
+pub struct Fetched {
+    // Where the response came from, after following any redirects (see `get`)
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+// The configured headers plus the ones reqwest adds (w/ the gzip & brotli features), set
+// explicitly so reqwest doesn't add any & the recorded request is what was actually sent
+fn request_headers() -> HeaderMap {
+    let mut headers = HEADERS.clone();
+    headers
+        .entry(ACCEPT)
+        .or_insert_with(|| HeaderValue::from_static("*/*"));
+    headers
+        .entry(ACCEPT_ENCODING)
+        .or_insert_with(|| HeaderValue::from_static("gzip, br"));
+    headers
+}
+
+// Renames the headers that describe how the body was sent, since the recorded body
+// isn't chunked or compressed anymore
+fn response_headers(headers: &HeaderMap, body_len: usize) -> HeaderMap {
+    let mut out = HeaderMap::new();
+    for (k, v) in headers {
+        if k == TRANSFER_ENCODING || k == CONTENT_ENCODING || k == CONTENT_LENGTH {
+            let orig = format!("x-archive-orig-{}", k);
+            out.append(HeaderName::from_bytes(orig.as_bytes()).unwrap(), v.clone());
+        } else {
+            out.append(k.clone(), v.clone());
+        }
+    }
+    out.insert(CONTENT_LENGTH, HeaderValue::from(body_len));
+    out
+}
+
+// Same as reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+// GETs the url, following redirects, & records each exchange if WARC recording is on
+// Redirects are followed here rather than by reqwest so every hop is recorded
+pub async fn get(url: &Url) -> Result<Fetched> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let fetched = get_once(&url).await?;
+        let redirect = matches!(
+            fetched.status,
+            StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT
+        );
+        let location = fetched.headers.get(LOCATION).and_then(|v| v.to_str().ok());
+        match location {
+            Some(location) if redirect => {
+                url = fetched.url.join(location)?;
+                url.set_fragment(None);
+            }
+            _ => return Ok(fetched),
+        }
+    }
+    bail!("Too many redirects, last: {}", url)
+}
+
+async fn get_once(url: &Url) -> Result<Fetched> {
+    let request = CLIENT.get(url.clone()).headers(request_headers()).build()?;
+    let sent = request.headers().clone();
+    let resp = CLIENT.execute(request).await?;
+    let status = resp.status();
+    let version = resp.version();
+    let headers = resp.headers().clone();
+    let body = resp.bytes().await?.to_vec();
+    let fetched = Fetched {
+        url: url.clone(),
+        status,
+        headers,
+        body,
+    };
+    if let Some(warc) = &*WARC {
+        // The page was fetched fine, so it's still used even if it can't be recorded
+        if let Err(e) = warc.record(version, &sent, &fetched).await {
+            error!("Failed to record: {} to the WARC: {:?}", url, e);
+        }
+    }
+    Ok(fetched)
+}
+
+struct Open {
+    file: File,
+    path: PathBuf,
+    bytes: u64,
+}
+
+struct State {
+    open: Option<Open>,
+    // Files are numbered from 0 in each run
+    seq: usize,
+}
+
+pub struct Warc {
+    config: Config,
+    // Start of the run, part of every file name
+    started: String,
+    state: Mutex<State>,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+fn record_id() -> String {
+    format!("<urn:uuid:{}>", Uuid::new_v4())
+}
+
+fn http_headers(out: &mut Vec<u8>, headers: &HeaderMap) {
+    for (k, v) in headers {
+        out.extend_from_slice(k.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(v.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+fn record(warc_type: &str, fields: &[(&str, &str)], block: &[u8]) -> Vec<u8> {
+    let mut out = format!("WARC/1.1\r\nWARC-Type: {}\r\n", warc_type);
+    for (k, v) in fields {
+        out.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    out.push_str(&format!("Content-Length: {}\r\n\r\n", block.len()));
+    let mut out = out.into_bytes();
+    out.extend_from_slice(block);
+    out.extend_from_slice(b"\r\n\r\n");
+    out
+}
+
+impl Warc {
+    pub fn new(config: Config) -> Warc {
+        std::fs::create_dir_all(&config.dir).unwrap();
+        let started = now().chars().filter(|c| c.is_ascii_digit()).collect();
+        Warc {
+            config,
+            started,
+            state: Mutex::new(State { open: None, seq: 0 }),
+        }
+    }
+
+    async fn record(&self, version: Version, sent: &HeaderMap, fetched: &Fetched) -> Result<()> {
+        let url = &fetched.url;
+        let date = now();
+        let request_id = record_id();
+        let response_id = record_id();
+
+        // Requests are HTTP/1.1 even if the server answers w/ 1.0
+        let request_version = match version {
+            Version::HTTP_2 => Version::HTTP_2,
+            _ => Version::HTTP_11,
+        };
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let mut request = format!(
+            "GET {} {:?}\r\nhost: {}\r\n",
+            &url[Position::BeforePath..Position::AfterQuery],
+            request_version,
+            host
+        )
+        .into_bytes();
+        http_headers(&mut request, sent);
+        let request = record(
+            "request",
+            &[
+                ("WARC-Record-ID", &request_id),
+                ("WARC-Date", &date),
+                ("WARC-Target-URI", url.as_str()),
+                ("WARC-Concurrent-To", &response_id),
+                ("Content-Type", "application/http;msgtype=request"),
+            ],
+            &request,
+        );
+
+        let mut response = format!(
+            "{:?} {} {}\r\n",
+            version,
+            fetched.status.as_u16(),
+            fetched.status.canonical_reason().unwrap_or_default()
+        )
+        .into_bytes();
+        http_headers(
+            &mut response,
+            &response_headers(&fetched.headers, fetched.body.len()),
+        );
+        response.extend_from_slice(&fetched.body);
+        let response = record(
+            "response",
+            &[
+                ("WARC-Record-ID", &response_id),
+                ("WARC-Date", &date),
+                ("WARC-Target-URI", url.as_str()),
+                ("Content-Type", "application/http;msgtype=response"),
+            ],
+            &response,
+        );
+
+        let mut bytes = Compression::Gzip.compress(response).await?;
+        bytes.extend(Compression::Gzip.compress(request).await?);
+        self.write(&bytes).await
+    }
+
+    async fn write(&self, bytes: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let full = match &state.open {
+            Some(open) => open.bytes + bytes.len() as u64 > self.config.max_bytes,
+            None => true,
+        };
+        if full {
+            if let Some(open) = state.open.take() {
+                finish(open).await?;
+            }
+            let open = self.open(state.seq).await?;
+            state.seq += 1;
+            state.open = Some(open);
+        }
+        let open = state.open.as_mut().unwrap();
+        open.file.write_all(bytes).await?;
+        open.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    // Starts a new file w/ a warcinfo record
+    async fn open(&self, seq: usize) -> Result<Open> {
+        let name = format!("crawl-{}-{:05}.warc.gz", self.started, seq);
+        let path = self.config.dir.join(format!("{}.open", name));
+        info!("Recording responses to: {:?}", path);
+        let fields = format!(
+            "software: get-training-data/{}\r\nformat: WARC File Format 1.1\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        let info = record(
+            "warcinfo",
+            &[
+                ("WARC-Record-ID", &record_id()),
+                ("WARC-Date", &now()),
+                ("WARC-Filename", &name),
+                ("Content-Type", "application/warc-fields"),
+            ],
+            fields.as_bytes(),
+        );
+        let info = Compression::Gzip.compress(info).await?;
+        let mut file = File::create(&path).await?;
+        file.write_all(&info).await?;
+        Ok(Open {
+            file,
+            path,
+            bytes: info.len() as u64,
+        })
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        if let Some(open) = self.state.lock().await.open.take() {
+            finish(open).await?;
+        }
+        Ok(())
+    }
+}
+
+// Syncs the file & drops the .open suffix
+async fn finish(mut open: Open) -> Result<()> {
+    open.file.flush().await?;
+    open.file.sync_all().await?;
+    tokio::fs::rename(&open.path, open.path.with_extension("")).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_encoding_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("10"));
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        let out = response_headers(&headers, 42);
+        assert_eq!(out.get(TRANSFER_ENCODING), None);
+        assert_eq!(out.get(CONTENT_ENCODING), None);
+        assert_eq!(out.get(CONTENT_LENGTH).unwrap(), "42");
+        assert_eq!(
+            out.get("x-archive-orig-transfer-encoding").unwrap(),
+            "chunked"
+        );
+        assert_eq!(out.get("x-archive-orig-content-encoding").unwrap(), "gzip");
+        assert_eq!(out.get("x-archive-orig-content-length").unwrap(), "10");
+        assert_eq!(out.get_all("set-cookie").iter().count(), 2);
+    }
+}